@@ -0,0 +1,242 @@
+// On-disk cache of loaded CSV files (--cache).
+//
+// A loaded CSV file is copied into a SQLite file in the cache directory. Later
+// runs copy the table back from that file instead of parsing the CSV again, as
+// long as the CSV file's size and modification time and the load options are
+// unchanged. Each CSV file has at most one cache entry, which is replaced when
+// it goes stale.
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{Connection, OpenFlags};
+
+use crate::{
+    copy_table, print_table, quote_identifier, rejects_table_name, uri_encode_path, write_database,
+    CsvOptions,
+};
+
+// Schema a cache file is attached as while its tables are copied
+const CACHE_SCHEMA: &str = "pirkle_cache";
+
+/// Directory of the cache: $XDG_CACHE_HOME/pirkle, or ~/.cache/pirkle
+pub fn cache_dir() -> Result<PathBuf, Box<dyn Error>> {
+    let base = match env::var_os("XDG_CACHE_HOME").filter(|d| Path::new(d).is_absolute()) {
+        Some(dir) => PathBuf::from(dir),
+        None => env::var_os("HOME")
+            .filter(|home| !home.is_empty())
+            .map(|home| PathBuf::from(home).join(".cache"))
+            .ok_or("Can't find the cache directory: neither XDG_CACHE_HOME nor HOME is set")?,
+    };
+    Ok(base.join("pirkle"))
+}
+
+// 64-bit FNV-1a hash. Unlike std's hashers, it gives the same cache file
+// name for a path with any build of pirkle.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The cache entry for a CSV file loaded with some options
+pub struct Entry {
+    /// The SQLite file holding the loaded table
+    file: PathBuf,
+    /// Everything the loaded table depends on; the entry is only used if
+    /// this is unchanged
+    key: String,
+    source: PathBuf,
+    size: u64,
+}
+
+impl Entry {
+    /// `stream` tells whether the file is loaded while it is read, which
+    /// infers types from fewer rows
+    pub fn new(path: &Path, options: &CsvOptions, stream: bool) -> Result<Entry, Box<dyn Error>> {
+        let source = fs::canonicalize(path)?;
+        let metadata = fs::metadata(&source)?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+        let key = format!(
+            "{} {} {} stream={} {:?}",
+            env!("CARGO_PKG_VERSION"),
+            metadata.len(),
+            mtime,
+            stream,
+            options
+        );
+
+        let name = fnv1a(source.as_os_str().as_encoded_bytes());
+        let file = cache_dir()?.join(format!("{:016x}.sqlite", name));
+        Ok(Entry {
+            file,
+            key,
+            source,
+            size: metadata.len(),
+        })
+    }
+
+    /// Copy the cached table (and its rejects table, if any) into the
+    /// working database as `table_name`. Returns false if there is no
+    /// up-to-date entry. The cache file is only attached while copying, as
+    /// SQLite allows just a few attached databases at a time.
+    pub fn load(&self, conn: &Connection, table_name: &str) -> Result<bool, Box<dyn Error>> {
+        if !self.file.exists() {
+            return Ok(false);
+        }
+        let uri = format!(
+            "file:{}?mode=ro",
+            uri_encode_path(&self.file.to_string_lossy())
+        );
+        conn.execute("ATTACH DATABASE ?1 AS ?2", [uri.as_str(), CACHE_SCHEMA])?;
+        let loaded = self.copy_tables(conn, table_name);
+        conn.execute("DETACH DATABASE ?1", [CACHE_SCHEMA])?;
+        loaded
+    }
+
+    fn copy_tables(&self, conn: &Connection, table_name: &str) -> Result<bool, Box<dyn Error>> {
+        let key = conn.query_row(
+            &format!("SELECT key FROM {}.pirkle_cache", CACHE_SCHEMA),
+            [],
+            |row| row.get::<_, String>(0),
+        );
+        if key.ok().as_deref() != Some(self.key.as_str()) {
+            return Ok(false);
+        }
+
+        let tables = [
+            ("data", table_name.to_string()),
+            ("rejects", rejects_table_name(table_name)),
+        ];
+        for (table, target) in tables {
+            if has_table(conn, CACHE_SCHEMA, table)? {
+                copy_table(conn, Some(CACHE_SCHEMA), table, "main", &target)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Copy the loaded table `table_name` (and its rejects table, if any)
+    /// into the cache, replacing an older entry for the same file
+    pub fn store(&self, conn: &Connection, table_name: &str) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(cache_dir()?)?;
+        write_database(conn, &self.file, |schema| {
+            let tables = [
+                (table_name.to_string(), "data"),
+                (rejects_table_name(table_name), "rejects"),
+            ];
+            for (source, target) in tables {
+                if has_table(conn, "main", &source)? {
+                    copy_table(conn, Some("main"), &source, schema, target)?;
+                }
+            }
+
+            conn.execute(
+                &format!(
+                    "CREATE TABLE {}.pirkle_cache (key TEXT, source TEXT, size INTEGER, cached_at TEXT)",
+                    schema
+                ),
+                [],
+            )?;
+            conn.execute(
+                &format!(
+                    "INSERT INTO {}.pirkle_cache VALUES (?1, ?2, ?3, datetime('now'))",
+                    schema
+                ),
+                rusqlite::params![self.key, self.source.to_string_lossy(), self.size as i64],
+            )?;
+            Ok(())
+        })
+    }
+}
+
+fn has_table(conn: &Connection, schema: &str, table: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        &format!(
+            "SELECT count(*) FROM {}.sqlite_master WHERE type = 'table' AND name = ?1",
+            quote_identifier(schema)
+        ),
+        [table],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|n| n > 0)
+}
+
+// Cache files in the cache directory, including partially written ones
+fn cache_files() -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let dir = cache_dir()?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|f| {
+        f.extension()
+            .is_some_and(|e| e == "sqlite" || e.to_string_lossy().starts_with("partial-"))
+    });
+    files.sort();
+    Ok(files)
+}
+
+/// Print the cached CSV files (`pirkle cache ls`)
+pub fn list() -> Result<(), Box<dyn Error>> {
+    let mut rows = Vec::new();
+    for file in cache_files()? {
+        if file.extension().is_some_and(|e| e != "sqlite") {
+            continue;
+        }
+        let conn = Connection::open_with_flags(&file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let (source, size, cached_at) = conn.query_row(
+            "SELECT source, size, cached_at FROM pirkle_cache",
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            },
+        )?;
+        let cached_size = fs::metadata(&file)?.len();
+        rows.push(vec![
+            Some(source),
+            Some(size.to_string()),
+            Some(cached_size.to_string()),
+            Some(cached_at),
+        ]);
+    }
+
+    let headers = ["source", "size", "cached_size", "cached_at"].map(String::from);
+    print_table(&headers, &rows);
+    Ok(())
+}
+
+/// Delete all cache files (`pirkle cache clear`)
+pub fn clear() -> Result<(), Box<dyn Error>> {
+    let files = cache_files()?;
+    for file in &files {
+        fs::remove_file(file)?;
+    }
+    println!(
+        "Removed {} cached file(s) from {}",
+        files.len(),
+        cache_dir()?.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+}
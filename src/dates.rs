@@ -0,0 +1,404 @@
+// Detection and normalization of date and timestamp columns.
+//
+// Dates are parsed with chrono rather than Polars' `try_parse_dates`, which
+// only knows day-first formats and silently turns unparsable values into nulls.
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use polars::prelude::*;
+
+/// Format to parse a date column with, given by --date-format
+#[derive(Clone, Debug)]
+pub enum DateFormat {
+    /// chrono strftime pattern, e.g. "%d/%m/%Y"
+    Pattern(String),
+    /// Seconds since the Unix epoch
+    Epoch,
+    /// Milliseconds since the Unix epoch
+    EpochMillis,
+}
+
+impl DateFormat {
+    pub fn parse(s: &str) -> DateFormat {
+        match s {
+            "epoch" => DateFormat::Epoch,
+            "epoch_ms" => DateFormat::EpochMillis,
+            _ => DateFormat::Pattern(s.to_string()),
+        }
+    }
+
+    pub fn is_epoch(&self) -> bool {
+        matches!(self, DateFormat::Epoch | DateFormat::EpochMillis)
+    }
+}
+
+/// Order of day and month in a pattern. A column whose values match both
+/// day-first and month-first patterns can't be interpreted safely.
+#[derive(Clone, Copy, PartialEq)]
+enum FieldOrder {
+    Ymd,
+    Dmy,
+    Mdy,
+}
+
+use FieldOrder::*;
+
+const DATE_PATTERNS: &[(&str, FieldOrder)] = &[
+    ("%Y-%m-%d", Ymd),
+    ("%Y/%m/%d", Ymd),
+    ("%d/%m/%Y", Dmy),
+    ("%d.%m.%Y", Dmy),
+    ("%d-%m-%Y", Dmy),
+    ("%m/%d/%Y", Mdy),
+];
+
+// `%.f` also matches values without fractional seconds
+const DATETIME_PATTERNS: &[(&str, FieldOrder)] = &[
+    ("%Y-%m-%dT%H:%M:%S%.f", Ymd),
+    ("%Y-%m-%d %H:%M:%S%.f", Ymd),
+    ("%Y-%m-%dT%H:%M", Ymd),
+    ("%Y-%m-%d %H:%M", Ymd),
+    ("%Y/%m/%d %H:%M:%S%.f", Ymd),
+    ("%Y/%m/%d %H:%M", Ymd),
+    ("%d/%m/%Y %H:%M:%S", Dmy),
+    ("%d/%m/%Y %H:%M", Dmy),
+    ("%d.%m.%Y %H:%M:%S", Dmy),
+    ("%d.%m.%Y %H:%M", Dmy),
+    ("%m/%d/%Y %H:%M:%S", Mdy),
+    ("%m/%d/%Y %H:%M", Mdy),
+    ("%m/%d/%Y %I:%M:%S %p", Mdy),
+    ("%m/%d/%Y %I:%M %p", Mdy),
+];
+
+// Timestamps with a UTC offset or "Z" suffix, converted to UTC
+const OFFSET_PATTERNS: &[&str] = &["%Y-%m-%dT%H:%M:%S%.f%#z", "%Y-%m-%d %H:%M:%S%.f%#z"];
+
+/// A way to parse a single value, tried against every value of a column
+#[derive(Clone, Copy)]
+enum Parser<'a> {
+    Date(&'a str, FieldOrder),
+    DateTime(&'a str, FieldOrder),
+    OffsetDateTime(&'a str),
+}
+
+enum Parsed {
+    Date(NaiveDate),
+    DateTime(NaiveDateTime),
+}
+
+impl Parser<'_> {
+    // Patterns with a time of day produce timestamps, others dates
+    fn for_pattern(pattern: &str) -> Parser<'_> {
+        let has_time = ["%H", "%I", "%M", "%S", "%T", "%R"]
+            .iter()
+            .any(|spec| pattern.contains(spec));
+        if pattern.contains("%z") || pattern.contains("%:z") || pattern.contains("%#z") {
+            Parser::OffsetDateTime(pattern)
+        } else if has_time {
+            Parser::DateTime(pattern, Ymd)
+        } else {
+            Parser::Date(pattern, Ymd)
+        }
+    }
+
+    fn pattern(&self) -> &str {
+        match *self {
+            Parser::Date(pattern, _) | Parser::DateTime(pattern, _) => pattern,
+            Parser::OffsetDateTime(pattern) => pattern,
+        }
+    }
+
+    fn parse(&self, value: &str) -> Option<Parsed> {
+        match *self {
+            Parser::Date(pattern, _) => NaiveDate::parse_from_str(value, pattern)
+                .ok()
+                .map(Parsed::Date),
+            Parser::DateTime(pattern, _) => NaiveDateTime::parse_from_str(value, pattern)
+                .ok()
+                .map(Parsed::DateTime),
+            Parser::OffsetDateTime(pattern) => {
+                // chrono doesn't accept "Z" as an offset
+                let value = match value.strip_suffix('Z') {
+                    Some(v) => format!("{}+00:00", v),
+                    None => value.to_string(),
+                };
+                DateTime::parse_from_str(&value, pattern)
+                    .ok()
+                    .map(|dt| Parsed::DateTime(dt.naive_utc()))
+            }
+        }
+    }
+
+    fn order(&self) -> FieldOrder {
+        match *self {
+            Parser::Date(_, order) | Parser::DateTime(_, order) => order,
+            Parser::OffsetDateTime(_) => Ymd,
+        }
+    }
+}
+
+/// Result of looking for dates in a column
+pub enum Detection {
+    /// The column converted to a Date or Datetime column, and the format
+    /// that matched all values
+    Dates(Series, DateFormat),
+    /// All values parse as both day-first and month-first dates
+    Ambiguous,
+    /// A value that doesn't parse, with its 0-based row
+    NotDates { row: usize, value: String },
+}
+
+/// Try to interpret a column as dates or timestamps. Uses `format` if given,
+/// otherwise the built-in patterns. Epoch formats accept integer columns,
+/// all other formats need a text column.
+pub fn detect(series: &Series, format: Option<&DateFormat>) -> PolarsResult<Detection> {
+    if let Some(format) = format.filter(|f| f.is_epoch()) {
+        return from_epoch(series, format);
+    }
+
+    let values = series.str()?;
+
+    // A column without any values isn't a date column
+    if values.null_count() == values.len() {
+        let value = String::new();
+        return Ok(Detection::NotDates { row: 0, value });
+    }
+
+    let parsers = match format {
+        Some(DateFormat::Pattern(pattern)) => vec![Parser::for_pattern(pattern)],
+        _ => DATETIME_PATTERNS
+            .iter()
+            .map(|&(p, order)| Parser::DateTime(p, order))
+            .chain(OFFSET_PATTERNS.iter().map(|&p| Parser::OffsetDateTime(p)))
+            .chain(
+                DATE_PATTERNS
+                    .iter()
+                    .map(|&(p, order)| Parser::Date(p, order)),
+            )
+            .collect(),
+    };
+
+    // Find the parsers that accept every value, remembering how far the
+    // best failing parser got for error messages
+    let mut matching = Vec::new();
+    let mut furthest_failure: Option<usize> = None;
+    for parser in parsers {
+        let failure = values
+            .into_iter()
+            .enumerate()
+            .find(|(_, v)| v.is_some_and(|v| parser.parse(v.trim()).is_none()))
+            .map(|(row, _)| row);
+        match failure {
+            None => matching.push(parser),
+            Some(row) => furthest_failure = furthest_failure.max(Some(row)),
+        }
+    }
+
+    let Some(parser) = matching.first().copied() else {
+        let row = furthest_failure.unwrap_or(0);
+        let value = values.get(row).unwrap_or_default().to_string();
+        return Ok(Detection::NotDates { row, value });
+    };
+
+    let orders = matching.iter().map(|p| p.order()).collect::<Vec<_>>();
+    if orders.contains(&Dmy) && orders.contains(&Mdy) {
+        return Ok(Detection::Ambiguous);
+    }
+
+    let format = DateFormat::Pattern(parser.pattern().to_string());
+    let parsed = values
+        .into_iter()
+        .map(|v| v.and_then(|v| parser.parse(v.trim())));
+    let series = match parser {
+        Parser::Date(..) => {
+            let days = parsed.map(|p| match p {
+                Some(Parsed::Date(date)) => Some(days_since_epoch(date)),
+                _ => None,
+            });
+            Int32Chunked::from_iter_options(series.name(), days)
+                .into_date()
+                .into_series()
+        }
+        _ => {
+            let micros = parsed.map(|p| match p {
+                Some(Parsed::DateTime(dt)) => Some(dt.and_utc().timestamp_micros()),
+                _ => None,
+            });
+            Int64Chunked::from_iter_options(series.name(), micros)
+                .into_datetime(TimeUnit::Microseconds, None)
+                .into_series()
+        }
+    };
+    Ok(Detection::Dates(series, format))
+}
+
+// Interpret integers (or text holding integers) as seconds or milliseconds
+// since the Unix epoch
+fn from_epoch(series: &Series, format: &DateFormat) -> PolarsResult<Detection> {
+    let ints = series.cast(&DataType::Int64)?;
+    let ints = ints.i64()?;
+    if let Some(row) = (0..series.len())
+        .find(|&i| !matches!(series.get(i), Ok(AnyValue::Null)) && ints.get(i).is_none())
+    {
+        let value = series.get(row)?.to_string();
+        return Ok(Detection::NotDates { row, value });
+    }
+
+    let factor = match format {
+        DateFormat::EpochMillis => 1_000,
+        _ => 1_000_000,
+    };
+    let micros = ints.into_iter().map(|v| v.map(|v| v * factor));
+    Ok(Detection::Dates(
+        Int64Chunked::from_iter_options(series.name(), micros)
+            .into_datetime(TimeUnit::Microseconds, None)
+            .into_series(),
+        format.clone(),
+    ))
+}
+
+/// Convert a single value to the ISO-8601 text stored for a column of type
+/// `dtype` (Date or Datetime), given the format the column was detected with
+pub fn normalize_value(value: &str, format: &DateFormat, dtype: &DataType) -> Option<String> {
+    let parsed = match format {
+        DateFormat::Pattern(pattern) => Parser::for_pattern(pattern).parse(value.trim())?,
+        _ => {
+            let factor = match format {
+                DateFormat::EpochMillis => 1_000,
+                _ => 1_000_000,
+            };
+            let micros = value.trim().parse::<i64>().ok()?.checked_mul(factor)?;
+            Parsed::DateTime(DateTime::from_timestamp_micros(micros)?.naive_utc())
+        }
+    };
+    Some(match (parsed, dtype) {
+        (Parsed::Date(date), DataType::Datetime(..)) => date.and_hms_opt(0, 0, 0)?.to_string(),
+        (Parsed::Date(date), _) => date.to_string(),
+        (Parsed::DateTime(dt), DataType::Date) => dt.date().to_string(),
+        (Parsed::DateTime(dt), _) => dt.to_string(),
+    })
+}
+
+fn days_since_epoch(date: NaiveDate) -> i32 {
+    (date - DateTime::UNIX_EPOCH.date_naive()).num_days() as i32
+}
+
+/// Canonical ISO-8601 text for a Polars date, e.g. "2024-01-31"
+pub fn format_date(days: i32) -> String {
+    // Display gives the ISO form and is much faster than format()
+    (DateTime::UNIX_EPOCH.date_naive() + chrono::Duration::days(days as i64)).to_string()
+}
+
+/// Canonical ISO-8601 text for a Polars timestamp, in the form SQLite's date
+/// functions produce: "2024-01-31 13:45:00", with fractional seconds if any
+pub fn format_datetime(value: i64, unit: TimeUnit) -> String {
+    let dt = match unit {
+        TimeUnit::Nanoseconds => Some(DateTime::from_timestamp_nanos(value)),
+        TimeUnit::Microseconds => DateTime::from_timestamp_micros(value),
+        TimeUnit::Milliseconds => DateTime::from_timestamp_millis(value),
+    };
+    match dt {
+        Some(dt) => dt.naive_utc().to_string(),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect_strings(values: &[Option<&str>], format: Option<&str>) -> Detection {
+        let series = values
+            .iter()
+            .copied()
+            .collect::<StringChunked>()
+            .into_series();
+        detect(&series, format.map(DateFormat::parse).as_ref()).unwrap()
+    }
+
+    // The detected values as ISO-8601 text, and the pattern that matched
+    fn dates(detection: Detection) -> (Vec<Option<String>>, String) {
+        let Detection::Dates(series, format) = detection else {
+            panic!("expected dates");
+        };
+        let values = series
+            .iter()
+            .map(|value| match value {
+                AnyValue::Date(days) => Some(format_date(days)),
+                AnyValue::Datetime(value, unit, _) => Some(format_datetime(value, unit)),
+                _ => None,
+            })
+            .collect();
+        let pattern = match format {
+            DateFormat::Pattern(pattern) => pattern,
+            DateFormat::Epoch => "epoch".to_string(),
+            DateFormat::EpochMillis => "epoch_ms".to_string(),
+        };
+        (values, pattern)
+    }
+
+    #[test]
+    fn detect_iso_dates() {
+        let (values, pattern) = dates(detect_strings(
+            &[Some("2024-01-31"), None, Some("2024-02-29")],
+            None,
+        ));
+        assert_eq!(
+            values,
+            [
+                Some("2024-01-31".to_string()),
+                None,
+                Some("2024-02-29".to_string())
+            ]
+        );
+        assert_eq!(pattern, "%Y-%m-%d");
+    }
+
+    #[test]
+    fn detect_day_first_dates() {
+        let (values, pattern) = dates(detect_strings(
+            &[Some("31/01/2024"), Some("01/02/2024")],
+            None,
+        ));
+        assert_eq!(values[1].as_deref(), Some("2024-02-01"));
+        assert_eq!(pattern, "%d/%m/%Y");
+    }
+
+    #[test]
+    fn detect_rejects_ambiguous_dates() {
+        let detection = detect_strings(&[Some("01/02/2024"), Some("03/04/2024")], None);
+        assert!(matches!(detection, Detection::Ambiguous));
+
+        // An explicit format settles it
+        let (values, _) = dates(detect_strings(&[Some("01/02/2024")], Some("%m/%d/%Y")));
+        assert_eq!(values[0].as_deref(), Some("2024-01-02"));
+    }
+
+    #[test]
+    fn detect_converts_offsets_to_utc() {
+        let values = [
+            Some("2024-01-31T12:00:00Z"),
+            Some("2024-01-31T14:30:00+02:00"),
+        ];
+        let (values, _) = dates(detect_strings(&values, None));
+        assert_eq!(values[0].as_deref(), Some("2024-01-31 12:00:00"));
+        assert_eq!(values[1].as_deref(), Some("2024-01-31 12:30:00"));
+    }
+
+    #[test]
+    fn detect_reports_the_first_value_that_isnt_a_date() {
+        let detection = detect_strings(&[Some("2024-01-31"), Some("soon")], None);
+        assert!(matches!(detection, Detection::NotDates { row: 1, value } if value == "soon"));
+
+        let detection = detect_strings(&[None, None], None);
+        assert!(matches!(detection, Detection::NotDates { .. }));
+    }
+
+    #[test]
+    fn detect_epoch_seconds() {
+        let series = Series::new("t", &[Some(0i64), None, Some(86_400)]);
+        let (values, pattern) = dates(detect(&series, Some(&DateFormat::Epoch)).unwrap());
+        assert_eq!(values[2].as_deref(), Some("1970-01-02 00:00:00"));
+        assert_eq!(values[1], None);
+        assert_eq!(pattern, "epoch");
+    }
+}
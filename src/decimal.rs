@@ -0,0 +1,662 @@
+// Exact decimal numbers for money columns (--exact-decimals).
+//
+// Decimal columns are stored as REAL, so comparisons and arithmetic keep
+// working in SQLite. A REAL holds every decimal with up to 15 significant
+// digits exactly enough to get it back from its shortest representation,
+// which is what the exact `sum` and `average` add up. Columns with more
+// precise values are loaded as TEXT instead.
+//
+// A query's `sum` and `average` of a decimal column are replaced by exact
+// aggregates, which return the result as text with the column's scale.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use polars::prelude::*;
+use prql_compiler::ast::generic::InterpolateItem;
+use prql_compiler::ir::pl::TableExternRef;
+use prql_compiler::ir::rq::{
+    fold_table, fold_transform, CId, Compute, Expr, ExprKind, RelationColumn, RelationKind,
+    RelationalQuery, RqFold, TId, TableDecl, TableRef, Transform,
+};
+use rusqlite::functions::{Aggregate, Context, FunctionFlags, WindowAggregate};
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{Connection, OptionalExtension};
+
+// More fractional digits than this aren't treated as a decimal
+const MAX_SCALE: u32 = 18;
+
+/// Significant digits a REAL holds exactly
+pub const MAX_PRECISION: u32 = 15;
+
+/// A decimal number stored as an integer scaled by 10^scale
+#[derive(Clone, Copy, Debug)]
+pub struct Decimal {
+    value: i128,
+    scale: u32,
+}
+
+impl Decimal {
+    /// Parse plain decimal notation like "-12.50". Exponents aren't accepted.
+    pub fn parse(s: &str) -> Option<Decimal> {
+        let s = s.trim();
+        let (negative, digits) = match s.as_bytes().first()? {
+            b'-' => (true, &s[1..]),
+            b'+' => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (int_part, frac_part) = digits.split_once('.').unwrap_or((digits, ""));
+        if int_part.is_empty() && frac_part.is_empty() {
+            return None;
+        }
+
+        let scale = frac_part.len() as u32;
+        if scale > MAX_SCALE {
+            return None;
+        }
+        let mut value: i128 = 0;
+        for b in int_part.bytes().chain(frac_part.bytes()) {
+            if !b.is_ascii_digit() {
+                return None;
+            }
+            value = value.checked_mul(10)?.checked_add((b - b'0') as i128)?;
+        }
+        Some(Decimal {
+            value: if negative { -value } else { value },
+            scale,
+        })
+    }
+
+    /// The decimal a float was written as, using its shortest representation
+    pub fn from_f64(f: f64) -> Option<Decimal> {
+        if !f.is_finite() {
+            return None;
+        }
+        Decimal::parse(&f.to_string())
+    }
+
+    fn from_int(i: i64) -> Decimal {
+        Decimal {
+            value: i as i128,
+            scale: 0,
+        }
+    }
+
+    /// Decimal from an unscaled integer, e.g. (1250, 2) for 12.50
+    pub fn new(value: i128, scale: u32) -> Decimal {
+        Decimal { value, scale }
+    }
+
+    /// Number of significant digits, as in SQL's DECIMAL(precision, scale)
+    pub fn precision(&self) -> u32 {
+        let digits = self
+            .value
+            .unsigned_abs()
+            .checked_ilog10()
+            .map_or(1, |d| d + 1);
+        digits.max(self.scale + 1)
+    }
+
+    /// Number of significant digits, ignoring trailing zeros
+    pub fn significant_digits(&self) -> u32 {
+        let mut value = self.value.unsigned_abs();
+        while value != 0 && value.is_multiple_of(10) {
+            value /= 10;
+        }
+        value.checked_ilog10().map_or(1, |d| d + 1)
+    }
+
+    /// The value scaled to `scale` fractional digits, which mustn't be
+    /// fewer than it already has
+    pub fn rescale(self, scale: u32) -> Option<Decimal> {
+        let factor = 10i128.checked_pow(scale.checked_sub(self.scale)?)?;
+        Some(Decimal {
+            value: self.value.checked_mul(factor)?,
+            scale,
+        })
+    }
+
+    fn checked_add(self, other: Decimal) -> Option<Decimal> {
+        let scale = self.scale.max(other.scale);
+        let (a, b) = (self.rescale(scale)?, other.rescale(scale)?);
+        Some(Decimal {
+            value: a.value.checked_add(b.value)?,
+            scale,
+        })
+    }
+
+    fn neg(self) -> Decimal {
+        Decimal {
+            value: -self.value,
+            scale: self.scale,
+        }
+    }
+
+    // Divide by a count, rounding half away from zero to `scale` digits
+    fn checked_div(self, divisor: i64, scale: u32) -> Option<Decimal> {
+        let n = self.rescale(scale)?.value;
+        let d = divisor as i128;
+        let (q, r) = (n / d, n % d);
+        let q = if r.abs() * 2 >= d.abs() {
+            q + n.signum() * d.signum()
+        } else {
+            q
+        };
+        Some(Decimal { value: q, scale }.trim())
+    }
+
+    // Drop trailing zeros from the fractional digits
+    fn trim(mut self) -> Decimal {
+        while self.scale > 0 && self.value % 10 == 0 {
+            self.value /= 10;
+            self.scale -= 1;
+        }
+        self
+    }
+
+    /// The nearest float
+    pub fn to_f64(self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = format!(
+            "{:0>width$}",
+            self.value.unsigned_abs(),
+            width = self.scale as usize + 1
+        );
+        let (int_part, frac_part) = digits.split_at(digits.len() - self.scale as usize);
+        let sign = if self.value < 0 { "-" } else { "" };
+        if frac_part.is_empty() {
+            write!(f, "{}{}", sign, int_part)
+        } else {
+            write!(f, "{}{}.{}", sign, int_part, frac_part)
+        }
+    }
+}
+
+/// Why a text column can't be converted to a Decimal column, with the 0-based
+/// row of the first value that is in the way
+#[derive(Debug, PartialEq)]
+pub enum NotDecimal {
+    /// The value isn't a plain decimal number
+    Invalid(usize),
+    /// The value has more significant digits than a REAL holds exactly
+    TooPrecise(usize),
+}
+
+/// Convert a text column to a Decimal column with the scale of its most
+/// precise value
+pub fn to_decimal_column(series: &Series) -> PolarsResult<Result<Series, NotDecimal>> {
+    let values = series.str()?;
+    let mut decimals = Vec::with_capacity(values.len());
+    for (row, value) in values.into_iter().enumerate() {
+        match value.map(Decimal::parse) {
+            Some(None) => return Ok(Err(NotDecimal::Invalid(row))),
+            Some(Some(d)) if d.significant_digits() > MAX_PRECISION => {
+                return Ok(Err(NotDecimal::TooPrecise(row)))
+            }
+            Some(Some(d)) => decimals.push(Some(d)),
+            None => decimals.push(None),
+        }
+    }
+
+    let scale = decimals
+        .iter()
+        .flatten()
+        .map(|d| d.scale)
+        .max()
+        .unwrap_or(0);
+    let mut scaled = Vec::with_capacity(decimals.len());
+    for d in decimals {
+        match d.map(|d| d.rescale(scale)) {
+            Some(None) => return Ok(Err(NotDecimal::Invalid(scaled.len()))),
+            Some(Some(d)) => scaled.push(Some(d)),
+            None => scaled.push(None),
+        }
+    }
+    let precision = scaled
+        .iter()
+        .flatten()
+        .map(Decimal::precision)
+        .max()
+        .unwrap_or(scale + 1);
+
+    let mantissas = scaled.iter().map(|d| d.map(|d| d.value));
+    Ok(Ok(Int128Chunked::from_iter_options(
+        series.name(),
+        mantissas,
+    )
+    .into_decimal_unchecked(Some(precision as usize), scale as usize)
+    .into_series()))
+}
+
+/// Format a value of a DECIMAL(p,s) column with its `scale`, e.g. "12.50"
+pub fn format_with_scale(value: ValueRef, scale: u32) -> Option<String> {
+    let decimal = match value {
+        ValueRef::Integer(i) => Decimal::from_int(i),
+        ValueRef::Real(f) => Decimal::from_f64(f)?,
+        _ => return None,
+    };
+    decimal.rescale(scale).map(|d| d.to_string())
+}
+
+/// Scale from a declared column type like "DECIMAL(10,2)". Loaded decimal
+/// columns are declared as "REAL DECIMAL(p,s)" to get REAL affinity, as
+/// SQLite would turn whole amounts into integers in a NUMERIC column.
+pub fn declared_scale(decl_type: &str) -> Option<u32> {
+    let start = decl_type.to_ascii_uppercase().find("DECIMAL(")? + "DECIMAL(".len();
+    let args = decl_type[start..].trim_end().strip_suffix(')')?;
+    let (_, scale) = args.split_once(',')?;
+    scale.trim().parse().ok()
+}
+
+/// Scale of a DECIMAL(p,s) column of a table or view, None for other
+/// columns. Without a schema, the table is looked up the way SQLite resolves
+/// an unqualified name.
+pub fn column_scale(
+    conn: &Connection,
+    schema: Option<&str>,
+    table: &str,
+    column: &str,
+) -> rusqlite::Result<Option<u32>> {
+    let decl_type = conn
+        .query_row(
+            "SELECT type FROM pragma_table_info(?1, ?2) WHERE name = ?3 COLLATE NOCASE",
+            (table, schema, column),
+            |row| row.get::<_, Option<String>>(0),
+        )
+        .optional()?
+        .flatten();
+    Ok(decl_type.as_deref().and_then(declared_scale))
+}
+
+/// The query with `sum` and `average` of decimal columns replaced by their
+/// exact versions, which are given the column's scale. None if it has none.
+/// `scale_of` gives the scale of a column of an input table, identified by
+/// its schema (if qualified) and name.
+pub fn exact_aggregates(
+    rq: &RelationalQuery,
+    scale_of: impl Fn(Option<&str>, &str, &str) -> Option<u32>,
+) -> Option<RelationalQuery> {
+    let inputs = rq
+        .tables
+        .iter()
+        .filter_map(|table| match &table.relation.kind {
+            RelationKind::ExternRef(TableExternRef::LocalTable(ident)) => Some((table.id, ident)),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut references = References::default();
+    if let RelationKind::Pipeline(transforms) = &rq.relation.kind {
+        references.fold_transforms(transforms.clone()).ok()?;
+    }
+
+    let mut replace = ExactAggregates {
+        scale_of: |tid: TId, column: &str| {
+            let ident = inputs.get(&tid)?;
+            scale_of(ident.path.last().map(String::as_str), &ident.name, column)
+        },
+        scales: HashMap::new(),
+        computed: references.0,
+        in_table: false,
+        replaced: false,
+    };
+    let rq = replace.fold_query(rq.clone()).ok()?;
+    replace.replaced.then_some(rq)
+}
+
+struct ExactAggregates<F> {
+    scale_of: F,
+    /// Scales of the decimal columns read so far, by id
+    scales: HashMap<CId, u32>,
+    /// Columns of the main pipeline that are computed with
+    computed: HashSet<CId>,
+    /// Whether a table the main pipeline reads from is being folded
+    in_table: bool,
+    replaced: bool,
+}
+
+// The columns that expressions of a pipeline refer to. A result returned
+// as text would be compared and sorted as text there.
+#[derive(Default)]
+struct References(HashSet<CId>);
+
+impl RqFold for References {
+    fn fold_cid(&mut self, cid: CId) -> prql_compiler::Result<CId> {
+        self.0.insert(cid);
+        Ok(cid)
+    }
+
+    fn fold_transform(&mut self, transform: Transform) -> prql_compiler::Result<Transform> {
+        match transform {
+            Transform::Select(_) | Transform::Aggregate { .. } => Ok(transform),
+            transform => fold_transform(self, transform),
+        }
+    }
+
+    fn fold_table_ref(&mut self, table_ref: TableRef) -> prql_compiler::Result<TableRef> {
+        Ok(table_ref)
+    }
+
+    fn fold_compute(&mut self, compute: Compute) -> prql_compiler::Result<Compute> {
+        self.fold_expr(compute.expr.clone())?;
+        if let Some(window) = &compute.window {
+            let range = &window.frame.range;
+            for expr in range.start.iter().chain(&range.end) {
+                self.fold_expr(expr.clone())?;
+            }
+            self.0.extend(&window.partition);
+            self.0.extend(window.sort.iter().map(|sort| sort.column));
+        }
+        Ok(compute)
+    }
+}
+
+impl<F: Fn(TId, &str) -> Option<u32>> RqFold for ExactAggregates<F> {
+    fn fold_table(&mut self, table: TableDecl) -> prql_compiler::Result<TableDecl> {
+        self.in_table = true;
+        fold_table(self, table)
+    }
+
+    fn fold_table_ref(&mut self, table_ref: TableRef) -> prql_compiler::Result<TableRef> {
+        for (column, id) in &table_ref.columns {
+            if let RelationColumn::Single(Some(name)) = column {
+                if let Some(scale) = (self.scale_of)(table_ref.source, name) {
+                    self.scales.insert(*id, scale);
+                }
+            }
+        }
+        Ok(table_ref)
+    }
+
+    fn fold_compute(&mut self, mut compute: Compute) -> prql_compiler::Result<Compute> {
+        if let ExprKind::Operator { name, args } = &compute.expr.kind {
+            let function = match name.as_str() {
+                "std.sum" => Some("exact_sum"),
+                "std.average" => Some("exact_avg"),
+                _ => None,
+            };
+            let scale = match args.as_slice() {
+                [Expr {
+                    kind: ExprKind::ColumnRef(id),
+                    ..
+                }] => self.scales.get(id).copied(),
+                _ => None,
+            };
+            if let (Some(function), Some(scale)) = (function, scale) {
+                // Results that are computed with are needed as numbers
+                let as_text = !self.in_table && !self.computed.contains(&compute.id);
+                let (prefix, suffix) = if as_text {
+                    ("", "")
+                } else {
+                    ("CAST(", " AS REAL)")
+                };
+                compute.expr.kind = ExprKind::SString(vec![
+                    InterpolateItem::String(format!("{}{}(", prefix, function)),
+                    InterpolateItem::Expr {
+                        expr: Box::new(args[0].clone()),
+                        format: None,
+                    },
+                    InterpolateItem::String(format!(", {}){}", scale, suffix)),
+                ]);
+                self.replaced = true;
+            }
+        }
+        // A column that is just renamed keeps its scale
+        if let ExprKind::ColumnRef(id) = &compute.expr.kind {
+            if let Some(&scale) = self.scales.get(id) {
+                self.scales.insert(compute.id, scale);
+            }
+        }
+        Ok(compute)
+    }
+}
+
+/// Register `exact_sum(x, scale)` and `exact_avg(x, scale)`, which add up a
+/// decimal column exactly and return the result as text with at least
+/// `scale` fractional digits, e.g. "1.30"
+pub fn register_aggregates(conn: &Connection) -> rusqlite::Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    conn.create_window_function("exact_sum", 2, flags, Exact { kind: Kind::Sum })?;
+    conn.create_window_function("exact_avg", 2, flags, Exact { kind: Kind::Avg })?;
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Sum,
+    Avg,
+}
+
+struct Exact {
+    kind: Kind,
+}
+
+/// Running state of an exact aggregate
+struct Accumulator {
+    /// Exact sum, None once a value had no exact form or the sum overflowed
+    exact: Option<Decimal>,
+    /// Floating-point sum as a fallback
+    float: f64,
+    count: i64,
+    /// Fewest fractional digits of the result
+    scale: u32,
+}
+
+// A numeric argument as a decimal (if it has an exact one) and a float.
+// Like SQLite, text that isn't a number counts as 0.
+fn numeric_arg(value: ValueRef) -> Option<(Option<Decimal>, f64)> {
+    match value {
+        ValueRef::Null => None,
+        ValueRef::Integer(i) => Some((Some(Decimal::from_int(i)), i as f64)),
+        ValueRef::Real(f) => Some((Decimal::from_f64(f), f)),
+        ValueRef::Text(t) => {
+            let text = String::from_utf8_lossy(t);
+            let decimal = Decimal::parse(&text);
+            let float = text.trim().parse().unwrap_or(0.0);
+            Some((decimal.or(Some(Decimal::from_int(0))), float))
+        }
+        ValueRef::Blob(_) => Some((Some(Decimal::from_int(0)), 0.0)),
+    }
+}
+
+impl Exact {
+    fn result(&self, acc: Option<&Accumulator>) -> rusqlite::Result<Value> {
+        let Some(acc) = acc.filter(|acc| acc.count > 0) else {
+            return Ok(Value::Null);
+        };
+
+        let (exact, float) = match self.kind {
+            Kind::Sum => (acc.exact, acc.float),
+            // Six more digits than the inputs, as SQL's DECIMAL division does
+            Kind::Avg => (
+                acc.exact
+                    .and_then(|d| d.checked_div(acc.count, acc.scale.max(d.scale) + 6)),
+                acc.float / acc.count as f64,
+            ),
+        };
+        Ok(match exact {
+            Some(d) => {
+                let d = d.rescale(acc.scale.max(d.scale)).unwrap_or(d);
+                Value::Text(d.to_string())
+            }
+            None => Value::Real(float),
+        })
+    }
+
+    fn add(acc: &mut Accumulator, value: ValueRef, sign: i64) {
+        let Some((decimal, float)) = numeric_arg(value) else {
+            return;
+        };
+        let decimal = decimal.map(|d| if sign < 0 { d.neg() } else { d });
+        acc.exact = acc
+            .exact
+            .zip(decimal)
+            .and_then(|(sum, d)| sum.checked_add(d));
+        acc.float += float * sign as f64;
+        acc.count += sign;
+    }
+}
+
+impl Aggregate<Accumulator, Value> for Exact {
+    fn init(&self, ctx: &mut Context<'_>) -> rusqlite::Result<Accumulator> {
+        let scale = ctx.get::<i64>(1)?;
+        Ok(Accumulator {
+            exact: Some(Decimal::from_int(0)),
+            float: 0.0,
+            count: 0,
+            scale: u32::try_from(scale).unwrap_or(0).min(MAX_SCALE),
+        })
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, acc: &mut Accumulator) -> rusqlite::Result<()> {
+        Exact::add(acc, ctx.get_raw(0), 1);
+        Ok(())
+    }
+
+    fn finalize(&self, _: &mut Context<'_>, acc: Option<Accumulator>) -> rusqlite::Result<Value> {
+        self.result(acc.as_ref())
+    }
+}
+
+impl WindowAggregate<Accumulator, Value> for Exact {
+    fn value(&self, acc: Option<&mut Accumulator>) -> rusqlite::Result<Value> {
+        self.result(acc.map(|acc| &*acc))
+    }
+
+    fn inverse(&self, ctx: &mut Context<'_>, acc: &mut Accumulator) -> rusqlite::Result<()> {
+        Exact::add(acc, ctx.get_raw(0), -1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> String {
+        Decimal::parse(s).map(|d| d.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn parse_plain_decimals() {
+        assert_eq!(parse("12.50"), "12.50");
+        assert_eq!(parse(" -0.05 "), "-0.05");
+        assert_eq!(parse("+7"), "7");
+        assert_eq!(parse(".5"), "0.5");
+        assert_eq!(parse("3."), "3");
+
+        for invalid in [
+            "",
+            "-",
+            ".",
+            "1e5",
+            "1.2.3",
+            "12,50",
+            "0.1234567890123456789",
+        ] {
+            assert!(Decimal::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn precision_and_significant_digits() {
+        let d = Decimal::parse("-120.50").unwrap();
+        assert_eq!(d.precision(), 5);
+        assert_eq!(d.significant_digits(), 4);
+        assert_eq!(Decimal::parse("0.05").unwrap().precision(), 3);
+    }
+
+    #[test]
+    fn checked_div_rounds_half_away_from_zero() {
+        let div = |s: &str, count, scale| {
+            let d = Decimal::parse(s).unwrap();
+            d.checked_div(count, scale).unwrap().to_string()
+        };
+        assert_eq!(div("10", 3, 2), "3.33");
+        assert_eq!(div("2", 3, 2), "0.67");
+        assert_eq!(div("1", 2, 0), "1");
+        assert_eq!(div("-1", 2, 0), "-1");
+        assert_eq!(div("-2", 3, 2), "-0.67");
+        // Trailing zeros are dropped
+        assert_eq!(div("1.00", 4, 6), "0.25");
+    }
+
+    #[test]
+    fn decimal_columns_take_the_largest_scale() {
+        let column = |values: &[&str]| {
+            let series = Series::new("amount", values);
+            to_decimal_column(&series).unwrap()
+        };
+        let series = column(&["1.5", "12.25"]).unwrap();
+        assert_eq!(series.dtype(), &DataType::Decimal(Some(4), Some(2)));
+
+        assert_eq!(column(&["1.5", "n/a"]).unwrap_err(), NotDecimal::Invalid(1));
+        assert_eq!(
+            column(&["1.5", "1234567890.1234567"]).unwrap_err(),
+            NotDecimal::TooPrecise(1)
+        );
+    }
+
+    #[test]
+    fn declared_scale_of_decimal_columns() {
+        assert_eq!(declared_scale("REAL DECIMAL(10,2)"), Some(2));
+        assert_eq!(declared_scale("decimal(5, 0)"), Some(0));
+        assert_eq!(declared_scale("REAL"), None);
+    }
+
+    #[test]
+    fn aggregates_are_exact() {
+        let conn = Connection::open_in_memory().unwrap();
+        register_aggregates(&conn).unwrap();
+        let query =
+            |sql: &str| -> Option<String> { conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+
+        let values = "(VALUES (0.1), (0.2), (1.0))";
+        assert_eq!(
+            query(&format!("SELECT exact_sum(column1, 2) FROM {}", values)).as_deref(),
+            Some("1.30")
+        );
+        assert_eq!(
+            query(&format!("SELECT exact_avg(column1, 2) FROM {}", values)).as_deref(),
+            Some("0.43333333")
+        );
+        assert_eq!(
+            query("SELECT exact_avg(column1, 0) FROM (VALUES (1), (2))").as_deref(),
+            Some("1.5")
+        );
+        assert_eq!(
+            query("SELECT exact_sum(column1, 2) FROM (VALUES (NULL))"),
+            None
+        );
+    }
+
+    #[test]
+    fn only_aggregates_of_decimal_columns_are_replaced() {
+        let sql = |prql: &str| {
+            let rq = prql_compiler::prql_to_pl(prql)
+                .and_then(prql_compiler::pl_to_rq)
+                .unwrap();
+            let rq = exact_aggregates(&rq, |_, _, column| (column == "price").then_some(2));
+            rq.map(|rq| prql_compiler::rq_to_sql(rq, &Default::default()).unwrap())
+        };
+
+        let replaced = sql("from p | aggregate {s = sum price, n = sum qty}").unwrap();
+        assert!(replaced.contains("exact_sum(price, 2)"), "{}", replaced);
+        assert!(replaced.contains("SUM(qty)"), "{}", replaced);
+
+        // Results that are compared are numbers
+        let compared = sql("from p | aggregate {s = sum price} | filter s > 1").unwrap();
+        assert!(
+            compared.contains("CAST(exact_sum(price, 2) AS REAL)"),
+            "{}",
+            compared
+        );
+
+        assert!(sql("from p | aggregate {n = sum qty}").is_none());
+    }
+}
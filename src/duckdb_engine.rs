@@ -0,0 +1,351 @@
+// Running queries with DuckDB (--engine duckdb, with the "duckdb" feature).
+//
+// The query is compiled for DuckDB's SQL dialect, and each input becomes a
+// view over DuckDB's own CSV, JSON or Parquet reader, so nothing is loaded
+// before the query runs. CSV options are passed on to the CSV reader where it
+// has an equivalent. The others, and all CSV options given for JSON or
+// Parquet files, are rejected rather than ignored.
+
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use duckdb::types::ValueRef;
+use duckdb::Connection;
+use polars::prelude::DataType;
+use prql_compiler as prqlc;
+use tempfile::NamedTempFile;
+
+use crate::dates::DateFormat;
+use crate::sniff::{self, Format};
+use crate::{
+    column_names, compile_prql, print_results, quote_identifier, CsvArgs, CsvOptions, InputFile,
+    OnError,
+};
+
+/// Run a query on the CSV inputs it reads with DuckDB
+pub fn run_query(
+    query: &str,
+    files: &[InputFile],
+    stdin_tables: &[(String, String)],
+    format: &str,
+    csv_args: &CsvArgs,
+) -> Result<(), Box<dyn Error>> {
+    let options = prqlc::Options::default()
+        .with_target(prqlc::Target::Sql(Some(prqlc::sql::Dialect::DuckDb)));
+    let query = compile_prql(query, &options)?;
+
+    if files.iter().any(InputFile::is_sqlite) {
+        return Err("--engine duckdb can't query SQLite files".into());
+    }
+    if csv_args.stream || csv_args.vtab || csv_args.cache {
+        return Err("--engine duckdb can't be used with --stream, --vtab or --cache".into());
+    }
+
+    let conn = Connection::open_in_memory()?;
+    for file in files {
+        let table_name = file.table_name();
+        if query.references(&table_name) {
+            let options = csv_args.options_for(&table_name, Some(&file.path))?;
+            create_view(&conn, &table_name, &file.path, &options)?;
+        }
+    }
+
+    // DuckDB reads files, so stdin data is passed on in a temporary one,
+    // which has to exist until the query has run
+    let used_stdin_tables = stdin_tables
+        .iter()
+        .filter(|(table_name, _)| query.references(table_name))
+        .collect::<Vec<_>>();
+    let mut stdin_file = None;
+    if !used_stdin_tables.is_empty() {
+        if atty::is(atty::Stream::Stdin) {
+            return Err("Stdin tables specified, but no data available from stdin".into());
+        }
+        let mut data = Vec::new();
+        io::stdin().read_to_end(&mut data)?;
+        if data.is_empty() {
+            return Err("Stdin tables specified, but no data received from stdin".into());
+        }
+        if sniff::detect(&data) == Format::Sqlite {
+            return Err("--engine duckdb can't query SQLite data".into());
+        }
+        // Created with a random name and readable only by the user
+        let mut file = NamedTempFile::with_prefix("pirkle-stdin-")?;
+        file.write_all(&data)?;
+        file.flush()?;
+        stdin_file = Some(file);
+    }
+    if let Some(file) = &stdin_file {
+        for (table_name, _) in used_stdin_tables {
+            let options = csv_args.options_for(table_name, None)?;
+            create_view(&conn, table_name, file.path(), &options)?;
+        }
+    }
+
+    // Values print as DuckDB casts them to text, except floating-point
+    // numbers, which print as they do with SQLite. The comment PRQL appends
+    // to the SQL ends at the line break.
+    let subquery = format!("(\n{}\n)", query.sql);
+    let columns = conn
+        .prepare(&format!("DESCRIBE SELECT * FROM {}", subquery))
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(query_error)?;
+    let select = columns
+        .iter()
+        .map(|(name, column_type)| match column_type.as_str() {
+            "DOUBLE" | "FLOAT" => quote_identifier(name),
+            _ => format!("CAST({0} AS VARCHAR) AS {0}", quote_identifier(name)),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut stmt = conn
+        .prepare(&format!("SELECT {} FROM {}", select, subquery))
+        .map_err(query_error)?;
+    let mut rows = stmt.query([]).map_err(query_error)?;
+    let mut collected_rows = Vec::new();
+    while let Some(row) = rows.next().map_err(query_error)? {
+        let values = (0..columns.len())
+            .map(|i| row.get_ref(i).map(format_value))
+            .collect::<Result<Vec<_>, _>>()?;
+        collected_rows.push(values);
+    }
+
+    let column_names = columns
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    print_results(format, &column_names, &collected_rows)
+}
+
+fn format_value(value: ValueRef) -> Option<String> {
+    match value {
+        ValueRef::Null => None,
+        ValueRef::Float(f) => Some(f.to_string()),
+        ValueRef::Double(f) => Some(f.to_string()),
+        ValueRef::Text(t) => Some(String::from_utf8_lossy(t).to_string()),
+        // Everything else was cast to text
+        _ => None,
+    }
+}
+
+fn query_error(error: duckdb::Error) -> Box<dyn Error> {
+    format!("DuckDB can't run this query: {}", error).into()
+}
+
+// Expose an input file as the view `table_name`, with column names rewritten
+// as for the other engines
+fn create_view(
+    conn: &Connection,
+    table_name: &str,
+    path: &Path,
+    options: &CsvOptions,
+) -> Result<(), Box<dyn Error>> {
+    let format = sniff::detect_file(path)?;
+    let reader = match format {
+        Format::Parquet => {
+            reject_csv_options(options, "Parquet")?;
+            format!("read_parquet({})", sql_string(&path.to_string_lossy()))
+        }
+        Format::Json => {
+            reject_csv_options(options, "JSON")?;
+            format!(
+                "read_json_auto({}, sample_size = {})",
+                sql_string(&path.to_string_lossy()),
+                options.infer_rows.map_or(-1, |n| n as i64)
+            )
+        }
+        Format::Gzip => csv_reader(path, options, true)?,
+        Format::Csv => csv_reader(path, options, false)?,
+        Format::Sqlite => return Err("--engine duckdb can't query SQLite data".into()),
+    };
+
+    let mut stmt = conn.prepare(&format!("DESCRIBE SELECT * FROM {}", reader))?;
+    let read_names = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let headers = match format {
+        Format::Gzip | Format::Csv => csv_headers(conn, path, options, format == Format::Gzip)?,
+        _ => read_names.clone(),
+    };
+    let headers = if headers.len() == read_names.len() {
+        headers
+    } else {
+        read_names.clone()
+    };
+    let (_, names) = column_names(
+        &headers.iter().map(String::as_str).collect::<Vec<_>>(),
+        options,
+    );
+    let columns = read_names
+        .iter()
+        .zip(&names)
+        .map(|(read_name, name)| {
+            format!(
+                "{} AS {}",
+                quote_identifier(read_name),
+                quote_identifier(name)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    conn.execute_batch(&format!(
+        "CREATE VIEW {} AS SELECT {} FROM {}",
+        quote_identifier(table_name),
+        columns,
+        reader
+    ))?;
+    Ok(())
+}
+
+// Fail if CSV options that DuckDB's JSON and Parquet readers have no
+// equivalent for are set. Column names are rewritten for every format.
+fn reject_csv_options(options: &CsvOptions, format: &str) -> Result<(), Box<dyn Error>> {
+    let option = if options.skip_rows > 0 {
+        "--skip-rows"
+    } else if options.skip_footer > 0 {
+        "--skip-footer"
+    } else if options.comment_char.is_some() {
+        "--comment-char"
+    } else if !options.null_values.is_empty() {
+        "--null-values"
+    } else if !options.column_types.is_empty() {
+        "--type"
+    } else if options.date_format.is_some() || !options.column_date_formats.is_empty() {
+        "--date-format"
+    } else if options.exact_decimals {
+        "--exact-decimals"
+    } else if options.on_error != OnError::Fail {
+        "--on-error"
+    } else {
+        return Ok(());
+    };
+    Err(format!(
+        "{} can't be used for {} files with --engine duckdb",
+        option, format
+    )
+    .into())
+}
+
+// A call of DuckDB's read_csv with the equivalents of the CSV options
+fn csv_reader(path: &Path, options: &CsvOptions, gzipped: bool) -> Result<String, Box<dyn Error>> {
+    let unsupported = |option: &str| -> Result<String, Box<dyn Error>> {
+        Err(format!("{} can't be used with --engine duckdb", option).into())
+    };
+    if options.skip_footer > 0 {
+        return unsupported("--skip-footer");
+    }
+    if options.exact_decimals {
+        return unsupported("--exact-decimals");
+    }
+    if options.on_error == OnError::Quarantine {
+        return unsupported("--on-error quarantine");
+    }
+    if !options.column_date_formats.is_empty() {
+        return unsupported("A column-specific --date-format");
+    }
+
+    let mut arguments = vec![
+        sql_string(&path.to_string_lossy()),
+        "header = true".to_string(),
+        format!("skip = {}", options.skip_rows),
+        format!(
+            "sample_size = {}",
+            options.infer_rows.map_or(-1, |n| n as i64)
+        ),
+    ];
+    if let Some(comment) = &options.comment_char {
+        arguments.push(format!("comment = {}", sql_string(comment)));
+    }
+    if !options.null_values.is_empty() {
+        let values = options
+            .null_values
+            .iter()
+            .map(|v| sql_string(v))
+            .collect::<Vec<_>>();
+        arguments.push(format!("nullstr = [{}]", values.join(", ")));
+    }
+    if !options.column_types.is_empty() {
+        let mut types = Vec::new();
+        for (header, dtype) in &options.column_types {
+            let Some(duckdb_type) = duckdb_type(dtype) else {
+                return unsupported(&format!("--type {}={}", header, dtype));
+            };
+            types.push(format!("{}: '{}'", sql_string(header), duckdb_type));
+        }
+        arguments.push(format!("types = {{{}}}", types.join(", ")));
+    }
+    match &options.date_format {
+        Some(DateFormat::Pattern(pattern)) => {
+            arguments.push(format!("dateformat = {}", sql_string(pattern)))
+        }
+        Some(_) => return unsupported("--date-format epoch or epoch_ms"),
+        None => {}
+    }
+    if options.on_error == OnError::Skip {
+        arguments.push("ignore_errors = true".to_string());
+    }
+    // DuckDB only tells compressed files by their extension
+    if gzipped {
+        arguments.push("compression = 'gzip'".to_string());
+    }
+    Ok(format!("read_csv({})", arguments.join(", ")))
+}
+
+// The header row of a CSV file as written. DuckDB numbers repeated headers
+// and names empty ones its own way, so the row is read as data instead.
+fn csv_headers(
+    conn: &Connection,
+    path: &Path,
+    options: &CsvOptions,
+    gzipped: bool,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut arguments = vec![
+        sql_string(&path.to_string_lossy()),
+        "header = false".to_string(),
+        "all_varchar = true".to_string(),
+        format!("skip = {}", options.skip_rows),
+    ];
+    if let Some(comment) = &options.comment_char {
+        arguments.push(format!("comment = {}", sql_string(comment)));
+    }
+    if gzipped {
+        arguments.push("compression = 'gzip'".to_string());
+    }
+    let mut stmt = conn.prepare(&format!(
+        "SELECT * FROM read_csv({}) LIMIT 1",
+        arguments.join(", ")
+    ))?;
+    let mut rows = stmt.query([])?;
+    let Some(row) = rows.next()? else {
+        return Ok(Vec::new());
+    };
+    let mut headers = Vec::new();
+    for i in 0..row.as_ref().column_count() {
+        headers.push(row.get::<_, Option<String>>(i)?.unwrap_or_default());
+    }
+    Ok(headers)
+}
+
+fn duckdb_type(dtype: &DataType) -> Option<&'static str> {
+    match dtype {
+        DataType::Int64 => Some("BIGINT"),
+        DataType::UInt64 => Some("UBIGINT"),
+        DataType::Float64 => Some("DOUBLE"),
+        DataType::String => Some("VARCHAR"),
+        DataType::Boolean => Some("BOOLEAN"),
+        DataType::Date => Some("DATE"),
+        DataType::Datetime(..) => Some("TIMESTAMP"),
+        _ => None,
+    }
+}
+
+fn sql_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
@@ -0,0 +1,199 @@
+// Indexes on the columns a query looks rows up by.
+//
+// Loaded CSV tables start without indexes, so SQLite scans a whole table for
+// every join and filter. Before a query runs, the columns it compares with
+// `==` in join conditions and filters are indexed, and so are the columns
+// given with --index. Only tables loaded into the working database can be
+// indexed: attached SQLite files are opened read-only, and tables read with
+// --vtab are virtual tables.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use prql_compiler::ir::pl::TableExternRef;
+use prql_compiler::ir::rq::{
+    CId, Expr, ExprKind, RelationColumn, RelationKind, RelationalQuery, TId, Transform,
+};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::quote_identifier;
+
+/// A column of a table, e.g. ("orders", "customer_id")
+pub type Key = (String, String);
+
+/// Columns of input tables that the query compares with `==` in joins and
+/// filters
+pub fn query_keys(rq: &RelationalQuery) -> Vec<Key> {
+    // Input tables, which the query refers to by id
+    let inputs = rq
+        .tables
+        .iter()
+        .filter_map(|table| match &table.relation.kind {
+            RelationKind::ExternRef(TableExternRef::LocalTable(ident)) if ident.path.is_empty() => {
+                Some((table.id, ident.name.clone()))
+            }
+            _ => None,
+        })
+        .collect::<HashMap<TId, String>>();
+
+    let mut keys = Vec::new();
+    let relations = rq.tables.iter().map(|t| &t.relation).chain([&rq.relation]);
+    for relation in relations {
+        if let RelationKind::Pipeline(transforms) = &relation.kind {
+            pipeline_keys(transforms, &inputs, &mut keys);
+        }
+    }
+    keys
+}
+
+fn pipeline_keys(transforms: &[Transform], inputs: &HashMap<TId, String>, keys: &mut Vec<Key>) {
+    // Columns of input tables by the ids they have in this pipeline
+    let mut columns = HashMap::new();
+    for transform in transforms {
+        if let Transform::From(table) | Transform::Join { with: table, .. } = transform {
+            let Some(table_name) = inputs.get(&table.source) else {
+                continue;
+            };
+            for (column, id) in &table.columns {
+                if let RelationColumn::Single(Some(name)) = column {
+                    columns.insert(*id, (table_name.clone(), name.clone()));
+                }
+            }
+        }
+    }
+
+    for transform in transforms {
+        match transform {
+            Transform::Filter(expr) | Transform::Join { filter: expr, .. } => {
+                equality_keys(expr, &columns, keys)
+            }
+            Transform::Loop(transforms) => pipeline_keys(transforms, inputs, keys),
+            _ => {}
+        }
+    }
+}
+
+// Columns compared with `==` in a condition, or in any part of a conjunction
+fn equality_keys(expr: &Expr, columns: &HashMap<CId, Key>, keys: &mut Vec<Key>) {
+    let ExprKind::Operator { name, args } = &expr.kind else {
+        return;
+    };
+    match name.as_str() {
+        "std.and" => {
+            for arg in args {
+                equality_keys(arg, columns, keys);
+            }
+        }
+        "std.eq" => {
+            for arg in args {
+                if let ExprKind::ColumnRef(id) = &arg.kind {
+                    keys.extend(columns.get(id).cloned());
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Parse a TABLE.COLUMN argument of --index
+pub fn parse_key(arg: &str) -> Result<Key, Box<dyn Error>> {
+    match arg.split_once('.') {
+        Some((table, column)) if !table.is_empty() && !column.is_empty() => {
+            Ok((table.to_string(), column.to_string()))
+        }
+        _ => Err(format!("Invalid --index '{}': expected TABLE.COLUMN", arg).into()),
+    }
+}
+
+/// Index the columns the query looks up by, skipping ones that can't be
+/// indexed, and the requested columns, which must exist in loaded tables
+pub fn create_indexes(
+    conn: &Connection,
+    query_keys: &[Key],
+    requested: &[Key],
+    verbose: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut indexed = HashSet::new();
+    let keys = query_keys
+        .iter()
+        .map(|key| (key, false))
+        .chain(requested.iter().map(|key| (key, true)));
+    for ((table, column), required) in keys {
+        if is_virtual_table(conn, table)? {
+            if required {
+                return Err(format!(
+                    "Can't index '{}.{}': tables read with --vtab can't be indexed",
+                    table, column
+                )
+                .into());
+            }
+            continue;
+        }
+        let Some(key) = loaded_column(conn, table, column)? else {
+            if required {
+                return Err(format!(
+                    "Can't index '{}.{}': no loaded CSV table '{}' with column '{}'",
+                    table, column, table, column
+                )
+                .into());
+            }
+            continue;
+        };
+        if !indexed.insert(key.clone()) {
+            continue;
+        }
+
+        let (table, column) = key;
+        let index = format!("pirkle_idx_{}_{}", table, column);
+        conn.execute(
+            &format!(
+                "CREATE INDEX IF NOT EXISTS main.{} ON {} ({})",
+                quote_identifier(&index),
+                quote_identifier(&table),
+                quote_identifier(&column)
+            ),
+            [],
+        )?;
+        if verbose {
+            eprintln!("Built index {} on {}({})", index, table, column);
+        }
+    }
+    Ok(())
+}
+
+// Whether a table of the working database is a virtual table. These are
+// listed as tables, too, but SQLite can't index them.
+fn is_virtual_table(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM main.sqlite_master
+         WHERE type = 'table' AND name = ?1 COLLATE NOCASE
+           AND sql LIKE 'CREATE VIRTUAL TABLE%')",
+        [table],
+        |row| row.get(0),
+    )
+}
+
+// The names of a column of a table in the working database, as they were
+// declared. SQLite compares names case-insensitively, as the query does.
+fn loaded_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<Option<Key>> {
+    let Some(table) = conn
+        .query_row(
+            "SELECT name FROM main.sqlite_master
+             WHERE type = 'table' AND name = ?1 COLLATE NOCASE
+               AND sql NOT LIKE 'CREATE VIRTUAL TABLE%'",
+            [table],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    let column = conn
+        .query_row(
+            "SELECT name FROM pragma_table_info(?1, 'main') WHERE name = ?2 COLLATE NOCASE",
+            [&table, column],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    Ok(column.map(|column| (table, column)))
+}
@@ -1,18 +1,49 @@
+mod cache;
+mod dates;
+mod decimal;
+#[cfg(feature = "duckdb")]
+mod duckdb_engine;
+mod index;
+mod polars_engine;
+mod pushdown;
+mod sniff;
+mod stream;
+mod vtab;
+
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
 use std::io::{self, Read};
-use std::path::PathBuf;
-
-use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use clap::{Args, Parser, Subcommand};
+use flate2::read::MultiGzDecoder;
+use polars::io::mmap::MmapBytesReader;
 use polars::prelude::*;
 use prql_compiler as prqlc;
-use rusqlite::{Connection, ToSql};
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::types::{ToSqlOutput, Value, ValueRef};
+use rusqlite::{Connection, DatabaseName};
+use serde_json::value::RawValue;
+
+use dates::{DateFormat, Detection};
+use sniff::Format;
 
 /// A command-line tool to query CSV and SQLite files using PRQL (PRQL Query Language)
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// Files to query (CSV or SQLite), or 'stdin' to read from standard input
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Files to query (CSV, JSON, Parquet or SQLite, recognized by their
+    /// content; gzipped files are unpacked), or 'stdin' to read from standard input.
+    /// Use 'db.sqlite:table1,table2' to expose only selected SQLite tables.
+    /// SQLite URIs like 'file:db.sqlite?immutable=1' are accepted as well
     #[arg(required = false)]
     files: Vec<PathBuf>,
 
@@ -35,17 +66,602 @@ struct Cli {
     /// Show generated SQL without executing
     #[arg(long)]
     show_sql: bool,
+
+    /// Engine that runs the query. polars runs it on the CSV data directly,
+    /// which is faster for large inputs, but supports less SQL and can't
+    /// query SQLite files. duckdb runs it with DuckDB, reading the CSV files
+    /// with DuckDB's reader; it needs pirkle built with the duckdb feature
+    #[arg(long, default_value = "sqlite", value_parser = ["sqlite", "polars", "duckdb"])]
+    engine: String,
+
+    /// Report on stderr what is done besides running the query, like which
+    /// indexes are built
+    #[arg(short, long)]
+    verbose: bool,
+
+    #[command(flatten)]
+    sql: SqlArgs,
+
+    #[command(flatten)]
+    db: DbArgs,
+
+    #[command(flatten)]
+    csv: CsvArgs,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "SQL output options")]
+struct SqlArgs {
+    /// SQL dialect for --show-sql, e.g. postgres, bigquery or snowflake.
+    /// Overrides a target in the query's 'prql' header; without either,
+    /// generic SQL is shown
+    #[arg(long, value_name = "DIALECT", requires = "show_sql")]
+    target: Option<String>,
+
+    /// Leave out the "Generated by PRQL compiler" comment
+    #[arg(long, requires = "show_sql")]
+    no_signature: bool,
+
+    /// Print the SQL on one line instead of formatting it
+    #[arg(long, requires = "show_sql")]
+    no_format: bool,
+}
+
+impl SqlArgs {
+    /// Compiler options for the SQL shown with --show-sql
+    fn compile_options(&self) -> Result<prqlc::Options, Box<dyn Error>> {
+        let mut options = prqlc::Options::default()
+            .with_format(!self.no_format)
+            .with_signature_comment(!self.no_signature);
+        if let Some(target) = &self.target {
+            // Both 'postgres' and PRQL's 'sql.postgres' are accepted
+            let name = target.to_lowercase();
+            let name = if name.starts_with("sql.") {
+                name
+            } else {
+                format!("sql.{}", name)
+            };
+            let target = name.parse::<prqlc::Target>().map_err(|_| {
+                let dialects = prqlc::Target::names()
+                    .iter()
+                    .map(|name| name.trim_start_matches("sql.").to_string())
+                    .collect::<Vec<_>>();
+                format!(
+                    "Unknown --target: '{}' (expected one of {})",
+                    target,
+                    dialects.join(", ")
+                )
+            })?;
+            options = options.with_target(target);
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Database options")]
+struct DbArgs {
+    /// Open SQLite files read-write instead of read-only
+    #[arg(long)]
+    writable: bool,
+
+    /// Keep the working database in a temporary file instead of memory, for
+    /// inputs that don't fit into memory. This happens automatically for
+    /// CSV inputs larger than 1 GiB
+    #[arg(long)]
+    temp_db: bool,
+
+    /// Directory for the temporary database and SQLite's other temporary
+    /// files, e.g. of large sorts. Implies --temp-db
+    #[arg(long, value_name = "DIR")]
+    workdir: Option<PathBuf>,
+
+    /// Write all loaded tables, with their inferred types, to a new SQLite
+    /// file. Without a query, the inputs are only loaded and saved
+    #[arg(long, value_name = "FILE")]
+    save_db: Option<PathBuf>,
+
+    /// Resume a session saved with --save-db: its tables can be queried like
+    /// loaded ones, and are saved again with --save-db
+    #[arg(long, value_name = "FILE")]
+    open_db: Option<PathBuf>,
+
+    /// Index a column of a loaded table before running the query, e.g.
+    /// orders.customer_id. Columns the query joins on or compares with ==
+    /// are indexed anyway
+    #[arg(long, value_name = "TABLE.COLUMN", value_delimiter = ',')]
+    index: Vec<String>,
+}
+
+// Schema a database opened with --open-db is attached as
+const SESSION_SCHEMA: &str = "session";
+
+// Total size of CSV inputs from which the working database is kept on disk
+const TEMP_DB_THRESHOLD: u64 = 1 << 30;
+
+impl DbArgs {
+    /// Whether any of the options is given
+    fn is_used(&self) -> bool {
+        self.writable
+            || self.temp_db
+            || self.workdir.is_some()
+            || self.save_db.is_some()
+            || self.open_db.is_some()
+            || !self.index.is_empty()
+    }
+
+    /// Open the working database. On disk, it's an anonymous temporary
+    /// file, which SQLite deletes right after creating it (on Windows, when
+    /// it's closed), so nothing is left behind even if pirkle is interrupted.
+    fn open(&self, input_size: u64) -> Result<Connection, Box<dyn Error>> {
+        if self.temp_db || self.workdir.is_some() || input_size > TEMP_DB_THRESHOLD {
+            let conn = Connection::open("")?;
+            if let Some(dir) = &self.workdir {
+                if !dir.is_dir() {
+                    return Err(format!("Not a directory: {}", dir.display()).into());
+                }
+                // The file is only created once the database gets large
+                conn.pragma_update(None, "temp_store_directory", dir.to_string_lossy())?;
+            }
+            // Keep a reasonable part of it cached in memory
+            conn.execute_batch("PRAGMA cache_size = -262144")?;
+            Ok(conn)
+        } else {
+            Ok(Connection::open_in_memory()?)
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the cache of loaded CSV files (see --cache)
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheAction {
+    /// List the cached CSV files
+    Ls,
+    /// Delete all cached CSV files
+    Clear,
+}
+
+/// CSV reading options. Each option applies to all CSV inputs, or to a single
+/// table when given as TABLE=VALUE. Table-specific values take precedence.
+#[derive(Args)]
+#[command(next_help_heading = "CSV options")]
+struct CsvArgs {
+    /// Skip N lines before the header row
+    #[arg(long, value_name = "[TABLE=]N")]
+    skip_rows: Vec<String>,
+
+    /// Ignore lines starting with this character
+    #[arg(long, value_name = "[TABLE=]CHAR")]
+    comment_char: Vec<String>,
+
+    /// Skip N lines at the end of the file, e.g. a "Total" footer
+    #[arg(long, value_name = "[TABLE=]N")]
+    skip_footer: Vec<String>,
+
+    /// Comma-separated values to load as NULL, e.g. 'NA,\N,' (an empty
+    /// entry stands for the empty string)
+    #[arg(long, value_name = "[TABLE=]LIST", allow_hyphen_values = true)]
+    null_values: Vec<String>,
+
+    /// Column types to use instead of the inferred ones, e.g. 'zip=TEXT,amount=REAL'.
+    /// Prefix a column with 'TABLE.' to restrict it to one table
+    #[arg(long = "type", value_name = "[TABLE.]COLUMN=TYPE,...")]
+    column_types: Vec<String>,
+
+    /// Number of rows to infer column types from, or 'all' to scan the whole file
+    /// [default: 100]
+    #[arg(long, value_name = "[TABLE=]N|all")]
+    infer_rows: Vec<String>,
+
+    /// Format of date columns, e.g. '%d/%m/%Y', or 'epoch'/'epoch_ms' for Unix
+    /// timestamps. Prefix with '[TABLE.]COLUMN=' to use it for one column only.
+    /// By default, common date formats are detected
+    #[arg(long, value_name = "[[TABLE.]COLUMN=]FORMAT")]
+    date_format: Vec<String>,
+
+    /// Rewrite column names: 'snake' turns "Order ID" into order_id, 'lower'
+    /// only lowercases. Duplicate and empty names are always made unique
+    #[arg(long, value_name = "[TABLE=]snake|lower|none")]
+    normalize_columns: Vec<String>,
+
+    /// What to do with malformed rows, e.g. with a wrong number of fields:
+    /// 'skip' them, 'quarantine' them in a TABLE__rejects table, or 'fail'
+    /// [default: fail]
+    #[arg(long, value_name = "[TABLE=]skip|quarantine|fail")]
+    on_error: Vec<String>,
+
+    /// Load CSV data while reading it instead of reading whole files and
+    /// stdin into memory first. Types are inferred from the first
+    /// --infer-rows rows; later values that don't fit are stored as text.
+    /// Compressed, JSON and Parquet inputs are read whole
+    #[arg(long)]
+    stream: bool,
+
+    /// Query CSV files in place through virtual tables instead of loading
+    /// them. Every scan reads the file again, which suits queries that read a
+    /// large file once. Types are inferred as with --stream; stdin and files
+    /// other than plain CSV are loaded
+    #[arg(long, conflicts_with = "stream")]
+    vtab: bool,
+
+    /// Keep loaded CSV files in a cache under $XDG_CACHE_HOME/pirkle, and use
+    /// it instead of parsing a file again while the file and these options
+    /// are unchanged. See 'pirkle cache --help'
+    #[arg(long, conflicts_with = "vtab")]
+    cache: bool,
+
+    /// Load decimal numbers like 12.50 exactly, keep their scale in the output
+    /// and compute sum, total and avg without floating-point errors
+    #[arg(long)]
+    exact_decimals: bool,
+}
+
+/// CSV reading options resolved for a single table
+#[derive(Clone, Debug)]
+struct CsvOptions {
+    /// Rows to infer column types from, None to scan all rows
+    infer_rows: Option<usize>,
+    skip_rows: usize,
+    comment_char: Option<String>,
+    skip_footer: usize,
+    null_values: Vec<String>,
+    /// Type overrides, keyed by the column header in the file
+    column_types: Vec<(String, DataType)>,
+    /// Header renames from a sidecar schema file (header, new name)
+    column_renames: Vec<(String, String)>,
+    /// Format to detect dates in all text columns with
+    date_format: Option<DateFormat>,
+    /// Date formats for single columns, keyed by the column header
+    column_date_formats: Vec<(String, DateFormat)>,
+    /// Load columns of decimal numbers as exact decimals
+    exact_decimals: bool,
+    normalize_columns: NameStyle,
+    on_error: OnError,
+}
+
+impl CsvOptions {
+    /// Whether the type of the column with this header was overridden
+    fn is_overridden(&self, header: &str) -> bool {
+        self.column_types.iter().any(|(h, _)| h == header)
+    }
+}
+
+/// How to handle malformed CSV rows
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OnError {
+    Fail,
+    Skip,
+    Quarantine,
+}
+
+/// How to rewrite column names from CSV headers
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NameStyle {
+    None,
+    Lower,
+    Snake,
+}
+
+impl NameStyle {
+    fn apply(self, header: &str) -> String {
+        match self {
+            NameStyle::None => header.to_string(),
+            NameStyle::Lower => header.trim().to_lowercase(),
+            NameStyle::Snake => to_snake_case(header),
+        }
+    }
+}
+
+// "Order ID" -> order_id, "customerName" -> customer_name, "Amount ($)" -> amount.
+// Names starting with a digit get a leading underscore to stay valid identifiers.
+fn to_snake_case(header: &str) -> String {
+    let mut name = String::new();
+    let mut separate = false;
+    let mut after_lower = false;
+    for c in header.chars() {
+        if !c.is_alphanumeric() {
+            separate = !name.is_empty();
+            after_lower = false;
+            continue;
+        }
+        if (separate || (c.is_uppercase() && after_lower)) && !name.is_empty() {
+            name.push('_');
+        }
+        separate = false;
+        name.extend(c.to_lowercase());
+        after_lower = c.is_lowercase() || c.is_ascii_digit();
+    }
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+impl CsvArgs {
+    /// Resolve the options for a table. `path` is the CSV file the table is
+    /// loaded from, which may have a sidecar schema file next to it.
+    fn options_for(&self, table: &str, path: Option<&Path>) -> Result<CsvOptions, Box<dyn Error>> {
+        let parse_count = |flag: &str, values: &[String]| -> Result<usize, Box<dyn Error>> {
+            match per_table_value(values, table) {
+                Some(v) => v
+                    .parse()
+                    .map_err(|_| format!("Invalid value for --{}: '{}'", flag, v).into()),
+                None => Ok(0),
+            }
+        };
+
+        let comment_char = per_table_value(&self.comment_char, table);
+        if comment_char.is_some_and(|c| c.chars().count() != 1) {
+            return Err(format!(
+                "Invalid value for --comment-char: '{}' (expected a single character)",
+                comment_char.unwrap()
+            )
+            .into());
+        }
+
+        let sidecar = match path {
+            Some(path) => read_sidecar_schema(path)?,
+            None => Vec::new(),
+        };
+        let column_renames = sidecar
+            .iter()
+            .filter_map(|c| c.header.clone().map(|h| (h, c.name.clone())))
+            .collect::<Vec<_>>();
+
+        // Columns can be given by their header or by the name from the sidecar file
+        let to_header = |column: String| {
+            column_renames
+                .iter()
+                .find(|(_, name)| *name == column)
+                .map_or(column, |(header, _)| header.clone())
+        };
+
+        // Types from the command line win over the sidecar file
+        let mut column_types = sidecar
+            .iter()
+            .filter_map(|c| {
+                let header = c.header.as_ref().unwrap_or(&c.name);
+                c.dtype.clone().map(|dtype| (header.clone(), dtype))
+            })
+            .collect::<Vec<_>>();
+        for (column, dtype) in self.type_overrides_for(table)? {
+            let header = to_header(column);
+            column_types.retain(|(h, _)| *h != header);
+            column_types.push((header, dtype));
+        }
+
+        let mut date_format = None;
+        let mut column_date_formats = Vec::new();
+        for spec in &self.date_format {
+            let Some((column, format)) = spec.split_once('=') else {
+                date_format = Some(DateFormat::parse(spec));
+                continue;
+            };
+            let column = match column.split_once('.') {
+                Some((t, column)) if t == table => column,
+                Some(_) => continue,
+                None => column,
+            };
+            column_date_formats.push((to_header(column.to_string()), DateFormat::parse(format)));
+        }
+        if date_format.as_ref().is_some_and(DateFormat::is_epoch) {
+            return Err(
+                "--date-format epoch and epoch_ms need a column, e.g. 'created=epoch'".into(),
+            );
+        }
+
+        let normalize_columns = match per_table_value(&self.normalize_columns, table) {
+            None | Some("none") => NameStyle::None,
+            Some("lower") => NameStyle::Lower,
+            Some("snake") => NameStyle::Snake,
+            Some(v) => {
+                return Err(format!(
+                    "Invalid value for --normalize-columns: '{}' (expected snake, lower or none)",
+                    v
+                )
+                .into())
+            }
+        };
+
+        let on_error = match per_table_value(&self.on_error, table) {
+            None | Some("fail") => OnError::Fail,
+            Some("skip") => OnError::Skip,
+            Some("quarantine") => OnError::Quarantine,
+            Some(v) => {
+                return Err(format!(
+                    "Invalid value for --on-error: '{}' (expected skip, quarantine or fail)",
+                    v
+                )
+                .into())
+            }
+        };
+
+        let infer_rows = match per_table_value(&self.infer_rows, table) {
+            Some("all") => None,
+            Some(_) => Some(parse_count("infer-rows", &self.infer_rows)?),
+            None => Some(100),
+        };
+
+        Ok(CsvOptions {
+            infer_rows,
+            skip_rows: parse_count("skip-rows", &self.skip_rows)?,
+            comment_char: comment_char.map(str::to_string),
+            skip_footer: parse_count("skip-footer", &self.skip_footer)?,
+            null_values: per_table_value(&self.null_values, table)
+                .map(|list| list.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+            column_types,
+            column_renames,
+            date_format,
+            column_date_formats,
+            exact_decimals: self.exact_decimals,
+            normalize_columns,
+            on_error,
+        })
+    }
+
+    // Collect the --type overrides that apply to a table
+    fn type_overrides_for(&self, table: &str) -> Result<Vec<(String, DataType)>, Box<dyn Error>> {
+        let mut overrides = Vec::new();
+        for spec in self.column_types.iter().flat_map(|list| list.split(',')) {
+            let (column, type_name) = spec.split_once('=').ok_or_else(|| {
+                format!(
+                    "Invalid value for --type: '{}' (expected COLUMN=TYPE)",
+                    spec
+                )
+            })?;
+            let dtype = parse_column_type(type_name)
+                .ok_or_else(|| format!("Unknown column type in --type: '{}'", type_name))?;
+
+            let column = match column.split_once('.') {
+                Some((t, column)) if t == table => column,
+                Some(_) => continue,
+                None => column,
+            };
+            overrides.push((column.trim().to_string(), dtype));
+        }
+        Ok(overrides)
+    }
+}
+
+// Map a SQL or CSVW type name to the Polars type to read the column as
+fn parse_column_type(name: &str) -> Option<DataType> {
+    match name.trim().to_lowercase().as_str() {
+        "integer" | "int" | "bigint" | "long" | "short" => Some(DataType::Int64),
+        "ubigint" | "unsignedlong" => Some(DataType::UInt64),
+        "real" | "float" | "double" | "number" | "numeric" => Some(DataType::Float64),
+        // Scale and precision are taken from the data
+        "decimal" => Some(DataType::Decimal(None, None)),
+        "text" | "string" | "varchar" => Some(DataType::String),
+        "boolean" | "bool" => Some(DataType::Boolean),
+        "date" => Some(DataType::Date),
+        "datetime" | "timestamp" => Some(DataType::Datetime(TimeUnit::Microseconds, None)),
+        _ => None,
+    }
+}
+
+/// A column pinned by a sidecar schema file
+struct SidecarColumn {
+    name: String,
+    /// Header in the CSV file, if the column should be renamed
+    header: Option<String>,
+    dtype: Option<DataType>,
+}
+
+// Read the sidecar schema for a CSV file if one exists. Looks for
+// `orders.schema.toml`, `orders.schema.json` and the CSVW convention
+// `orders.csv-metadata.json` next to `orders.csv`.
+fn read_sidecar_schema(path: &Path) -> Result<Vec<SidecarColumn>, Box<dyn Error>> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let candidates = [
+        format!("{}.schema.toml", stem),
+        format!("{}.schema.json", stem),
+        format!("{}-metadata.json", file_name),
+    ];
+
+    let Some(sidecar) = candidates
+        .iter()
+        .map(|name| path.with_file_name(name))
+        .find(|p| p.exists())
+    else {
+        return Ok(Vec::new());
+    };
+
+    let content = fs::read_to_string(&sidecar)?;
+    let invalid = |msg: String| format!("Invalid schema file {}: {}", sidecar.display(), msg);
+    let doc: serde_json::Value = if sidecar.extension().is_some_and(|e| e == "toml") {
+        toml::from_str(&content).map_err(|e| invalid(e.to_string()))?
+    } else {
+        serde_json::from_str(&content).map_err(|e| invalid(e.to_string()))?
+    };
+
+    // Both `columns = [...]` and CSVW's `tableSchema.columns` are accepted
+    let columns = doc
+        .get("columns")
+        .or_else(|| doc.get("tableSchema").and_then(|t| t.get("columns")))
+        .and_then(|c| c.as_array())
+        .ok_or_else(|| invalid("expected a list of columns".to_string()))?;
+
+    columns
+        .iter()
+        .map(|column| {
+            let name = column
+                .get("name")
+                .and_then(|n| n.as_str())
+                .ok_or_else(|| invalid("column without a name".to_string()))?;
+
+            // CSVW allows a list of titles and a datatype object with a base type
+            let header = column
+                .get("header")
+                .or_else(|| column.get("titles"))
+                .and_then(|t| t.as_str().or_else(|| t.get(0).and_then(|t| t.as_str())));
+            let type_name = column
+                .get("type")
+                .or_else(|| column.get("datatype"))
+                .and_then(|t| {
+                    t.as_str()
+                        .or_else(|| t.get("base").and_then(|b| b.as_str()))
+                });
+            let dtype = type_name
+                .map(|t| {
+                    parse_column_type(t).ok_or_else(|| {
+                        invalid(format!("unknown type '{}' for column '{}'", t, name))
+                    })
+                })
+                .transpose()?;
+
+            Ok(SidecarColumn {
+                name: name.to_string(),
+                header: header.filter(|h| *h != name).map(str::to_string),
+                dtype,
+            })
+        })
+        .collect()
+}
+
+// Pick the value for a table from "VALUE" and "TABLE=VALUE" arguments
+fn per_table_value<'a>(values: &'a [String], table: &str) -> Option<&'a str> {
+    let mut global = None;
+    for value in values {
+        match value.split_once('=') {
+            Some((name, v)) if !name.is_empty() => {
+                if name == table {
+                    return Some(v);
+                }
+            }
+            _ => global = Some(value.as_str()),
+        }
+    }
+    global
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
+    if let Some(Command::Cache { action }) = cli.command {
+        return match action {
+            CacheAction::Ls => cache::list(),
+            CacheAction::Clear => cache::clear(),
+        };
+    }
+
     // Process file arguments to identify stdin markers
-    let (regular_files, stdin_tables) = process_file_arguments(&cli.files)?;
+    // A session opened with --open-db is an input, too
+    let (regular_files, stdin_tables) =
+        process_file_arguments(&cli.files, cli.db.open_db.is_none())?;
 
     // Check for explicit schema request
     if cli.schema {
-        return show_schemas(&regular_files, &stdin_tables);
+        return show_schemas(&regular_files, &stdin_tables, &cli.db, &cli.csv);
     }
 
     // Determine the query source (prioritize --query over --)
@@ -53,7 +669,8 @@ fn main() -> Result<(), Box<dyn Error>> {
         // Only use stdin for query if not being used for data and it's not a terminal
         if stdin_tables.is_empty() && atty::isnt(atty::Stream::Stdin) {
             let mut buffer = String::new();
-            if let Ok(_) = io::stdin().read_to_string(&mut buffer) {
+            // Empty input, e.g. from a script without a terminal, isn't a query
+            if io::stdin().read_to_string(&mut buffer).is_ok() && !buffer.trim().is_empty() {
                 Some(buffer)
             } else {
                 None
@@ -63,32 +680,149 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     });
 
+    // Without a query, the inputs can still be saved with --save-db
+    let save_only = query.is_none() && cli.db.save_db.is_some();
+
     // If no query but files specified (including stdin markers), show schema
-    if query.is_none() && (!regular_files.is_empty() || !stdin_tables.is_empty()) {
-        return show_schemas(&regular_files, &stdin_tables);
+    if query.is_none() && !save_only && (!regular_files.is_empty() || !stdin_tables.is_empty()) {
+        return show_schemas(&regular_files, &stdin_tables, &cli.db, &cli.csv);
     }
 
     // If no query and no files, show help
-    if query.is_none() {
+    if query.is_none() && !save_only {
         eprintln!("Error: No query provided. Use --query, -- delimiter, or pipe a query.");
         eprintln!("Run with --help for usage information.");
         std::process::exit(1);
     }
 
+    if cli.show_sql {
+        if let Some(query) = &query {
+            println!("{}", compile_prql(query, &cli.sql.compile_options()?)?.sql);
+        }
+        return Ok(());
+    }
+
+    if cli.engine != "sqlite" {
+        if cli.db.is_used() {
+            return Err(format!(
+                "Database options can't be used with --engine {}",
+                cli.engine
+            )
+            .into());
+        }
+        let Some(query) = &query else {
+            return Err(format!("--engine {} needs a query", cli.engine).into());
+        };
+        if cli.engine == "duckdb" {
+            #[cfg(feature = "duckdb")]
+            return duckdb_engine::run_query(
+                query,
+                &regular_files,
+                &stdin_tables,
+                &cli.format,
+                &cli.csv,
+            );
+            #[cfg(not(feature = "duckdb"))]
+            return Err(
+                "This pirkle was built without DuckDB; build it with '--features duckdb' to use --engine duckdb"
+                    .into(),
+            );
+        }
+        return polars_engine::run_query(
+            &compile_prql(query, &engine_options())?,
+            &regular_files,
+            &stdin_tables,
+            &cli.format,
+            &cli.csv,
+        );
+    }
+
     // Run the query with both regular files and stdin tables
     run_query(
-        &query.unwrap(),
+        query.as_deref(),
         &regular_files,
         &stdin_tables,
         &cli.format,
-        cli.show_sql,
+        cli.verbose,
+        &cli.db,
+        &cli.csv,
     )
 }
 
-// Function to process file arguments and identify stdin markers
+/// A data file given on the command line
+struct InputFile {
+    path: PathBuf,
+    /// Query parameters if the file was given as a `file:` URI (SQLite only)
+    uri_params: Option<String>,
+    /// Tables selected with `db.sqlite:table1,table2` (SQLite only)
+    tables: Option<Vec<String>>,
+}
+
+impl InputFile {
+    /// Table name (CSV) or schema name (SQLite) derived from the file stem
+    fn table_name(&self) -> String {
+        self.path.file_stem().unwrap().to_string_lossy().to_string()
+    }
+
+    /// Stand-in for SQLite data piped to stdin, which has no file
+    fn stdin() -> Self {
+        InputFile {
+            path: PathBuf::from("stdin"),
+            uri_params: None,
+            tables: None,
+        }
+    }
+
+    fn is_sqlite(&self) -> bool {
+        self.uri_params.is_some() || is_sqlite_file(&self.path)
+    }
+
+    /// SQLite URI used to open the file. Databases are opened read-only
+    /// unless `writable` is set or the URI specifies a mode itself.
+    fn sqlite_uri(&self, writable: bool) -> String {
+        let mut params = self
+            .uri_params
+            .as_deref()
+            .unwrap_or("")
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .collect::<Vec<_>>();
+        if !writable && !params.iter().any(|p| p.starts_with("mode=")) {
+            params.push("mode=ro");
+        }
+
+        let mut uri = format!("file:{}", uri_encode_path(&self.path.to_string_lossy()));
+        if !params.is_empty() {
+            uri.push('?');
+            uri.push_str(&params.join("&"));
+        }
+        uri
+    }
+}
+
+// SQLite files are recognized by their header. An empty file is a valid
+// database, too, which only its extension tells apart from an empty CSV file.
+fn is_sqlite_file(path: &Path) -> bool {
+    match sniff::detect_file(path) {
+        Ok(Format::Sqlite) => true,
+        Ok(_) => {
+            path.metadata().is_ok_and(|m| m.len() == 0)
+                && path
+                    .extension()
+                    .is_some_and(|e| e == "sqlite" || e == "sqlite3" || e == "db")
+        }
+        Err(_) => false,
+    }
+}
+
+type FileArguments = (Vec<InputFile>, Vec<(String, String)>);
+
+// Function to process file arguments and identify stdin markers. Without any
+// inputs, piped data is read as a stdin table if `implicit_stdin` is set.
 fn process_file_arguments(
     files: &[PathBuf],
-) -> Result<(Vec<PathBuf>, Vec<(String, String)>), Box<dyn Error>> {
+    implicit_stdin: bool,
+) -> Result<FileArguments, Box<dyn Error>> {
     let mut regular_files = Vec::new();
     let mut stdin_tables = Vec::new();
 
@@ -105,38 +839,184 @@ fn process_file_arguments(
             } else {
                 return Err("Invalid stdin table specification: empty name after 'stdin:'".into());
             }
+        } else if file_arg.exists() {
+            regular_files.push(InputFile {
+                path: file_arg.clone(),
+                uri_params: None,
+                tables: None,
+            });
+        } else if let Some((path, uri_params)) = parse_sqlite_uri(&file_str) {
+            regular_files.push(InputFile {
+                path,
+                uri_params: Some(uri_params),
+                tables: None,
+            });
+        } else if let Some(input) = parse_table_selection(&file_str) {
+            regular_files.push(input?);
         } else {
-            // Regular file - validate it exists
-            if !file_arg.exists() {
-                return Err(format!("File not found: {}", file_arg.display()).into());
-            }
-            regular_files.push(file_arg.clone());
+            return Err(format!("File not found: {}", file_arg.display()).into());
         }
     }
 
     // If no stdin tables but also no files specified, and stdin is not a terminal,
     // implicitly add a stdin table
-    if stdin_tables.is_empty() && regular_files.is_empty() && atty::isnt(atty::Stream::Stdin) {
-        // Check if there's data in stdin before assuming it's for data input
-        // We'll just peek at the first byte without consuming it
-        let stdin = io::stdin();
-        let mut handle = stdin.lock();
-
-        // Try to peek if there's data
-        let mut peek_buf = [0; 1];
-        if handle.read_exact(&mut peek_buf).is_ok() {
-            // There's data, so add default stdin table
+    if implicit_stdin
+        && stdin_tables.is_empty()
+        && regular_files.is_empty()
+        && atty::isnt(atty::Stream::Stdin)
+    {
+        // Check if there's data in stdin before assuming it's for data input.
+        // Peeking leaves the data in stdin's buffer for later reads.
+        if sniff::peek_stdin()?.is_some() {
             stdin_tables.push(("stdin".to_string(), "stdin".to_string()));
         }
-        // Note: We've consumed a byte, but it'll be buffered and available for later reads
     }
 
     Ok((regular_files, stdin_tables))
 }
 
+// Parse "file:path?param=value" into the local path and the query parameters.
+// Returns None if the argument isn't a URI naming an existing file.
+fn parse_sqlite_uri(arg: &str) -> Option<(PathBuf, String)> {
+    let rest = arg.strip_prefix("file:")?;
+    let (path, params) = rest.split_once('?').unwrap_or((rest, ""));
+    let path = path.split_once('#').map_or(path, |(p, _)| p);
+
+    // Only an empty or "localhost" authority is allowed by SQLite
+    let path = match path.strip_prefix("//") {
+        Some(with_authority) => with_authority
+            .strip_prefix("localhost")
+            .unwrap_or(with_authority),
+        None => path,
+    };
+
+    let path = PathBuf::from(uri_decode_path(path)?);
+    if !path.exists() {
+        return None;
+    }
+    Some((
+        path,
+        params
+            .split_once('#')
+            .map_or(params, |(p, _)| p)
+            .to_string(),
+    ))
+}
+
+fn uri_decode_path(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = path.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Escape the characters that have a special meaning in SQLite URIs
+fn uri_encode_path(path: &str) -> String {
+    path.replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23")
+}
+
+// Parse "db.sqlite:table1,table2". Returns None if the argument doesn't name
+// an existing SQLite file followed by a table list.
+fn parse_table_selection(arg: &str) -> Option<Result<InputFile, Box<dyn Error>>> {
+    let (file, tables) = arg.rsplit_once(':')?;
+    let (path, uri_params) = match parse_sqlite_uri(file) {
+        Some((path, params)) => (path, Some(params)),
+        None => (PathBuf::from(file), None),
+    };
+    if !path.exists() || (uri_params.is_none() && !is_sqlite_file(&path)) {
+        return None;
+    }
+
+    let tables = tables
+        .split(',')
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>();
+    if tables.is_empty() {
+        return Some(Err(format!(
+            "Invalid table selection for {}: empty table list after ':'",
+            file
+        )
+        .into()));
+    }
+
+    Some(Ok(InputFile {
+        path,
+        uri_params,
+        tables: Some(tables),
+    }))
+}
+
+// List the user tables of a database schema ("main" or an attached name),
+// restricted to the tables selected for the file if a selection was given
+fn list_sqlite_tables(
+    conn: &Connection,
+    schema: &str,
+    file: &InputFile,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut tbl_stmt = conn.prepare(&format!(
+        "SELECT name
+               FROM {}.sqlite_master
+              WHERE type='table'
+                AND name NOT LIKE 'sqlite_%';",
+        quote_identifier(schema)
+    ))?;
+    let table_names = tbl_stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let Some(selection) = &file.tables else {
+        return Ok(table_names);
+    };
+
+    // Keep the order given on the command line, but use the stored spelling
+    selection
+        .iter()
+        .map(|wanted| {
+            table_names
+                .iter()
+                .find(|name| name.eq_ignore_ascii_case(wanted))
+                .cloned()
+                .ok_or_else(|| {
+                    format!("Table '{}' not found in {}", wanted, file.path.display()).into()
+                })
+        })
+        .collect()
+}
+
+// Names that occur more than once across all inputs can't be used unqualified.
+// SQLite identifiers are case-insensitive, so compare lowercased.
+fn ambiguous_names<'a>(names: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for name in names {
+        *counts.entry(name.to_lowercase()).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
 // Function to convert Polars DataType to SQLite type string
-fn polars_to_sqlite_type(dtype: &DataType) -> &'static str {
-    match dtype {
+fn polars_to_sqlite_type(dtype: &DataType) -> String {
+    let sqlite_type = match dtype {
         DataType::Int8
         | DataType::Int16
         | DataType::Int32
@@ -146,77 +1026,94 @@ fn polars_to_sqlite_type(dtype: &DataType) -> &'static str {
         | DataType::UInt32
         | DataType::UInt64 => "INTEGER",
         DataType::Float32 | DataType::Float64 => "REAL",
+        // Exact decimals keep their scale in the declared type for the output
+        DataType::Decimal(Some(precision), Some(scale)) => {
+            return format!("REAL DECIMAL({},{})", precision, scale)
+        }
         DataType::Decimal(..) => "REAL",
-        DataType::Date | DataType::Datetime(..) => "TEXT", // Could use INTEGER for Unix timestamp
+        // Stored as ISO-8601 text, which SQLite's date functions understand
+        DataType::Date => "DATE",
+        DataType::Datetime(..) => "DATETIME",
         DataType::Time => "TEXT",
-        DataType::Boolean => "INTEGER", // SQLite has no Boolean, use INTEGER (0/1)
+        // SQLite has no Boolean and stores 0/1, but the declared type lets
+        // the output show true/false
+        DataType::Boolean => "BOOLEAN",
         DataType::String => "TEXT",
-        DataType::List(_) => "TEXT", // Store lists as JSON text
+        DataType::List(_) | DataType::Struct(_) => "TEXT", // Store nested values as JSON text
         DataType::Binary => "BLOB",
         _ => "TEXT", // Default to TEXT for any other types
-    }
+    };
+    sqlite_type.to_string()
 }
 
 fn show_schemas(
-    files: &[PathBuf],
+    files: &[InputFile],
     stdin_tables: &[(String, String)],
+    db_args: &DbArgs,
+    csv_args: &CsvArgs,
 ) -> Result<(), Box<dyn Error>> {
-    // First show schemas for regular files
-    for file in files {
-
-        if file
-            .extension()
-            .map(|e| e == "sqlite" || e == "db")
-            .unwrap_or(false)
-        {
-            // 1) Open the database
-            let conn = Connection::open(file)?;
-
-            // 2) List all user tables
-            let mut tbl_stmt = conn.prepare(
-                "SELECT name
-                       FROM sqlite_master
-                      WHERE type='table'
-                        AND name NOT LIKE 'sqlite_%';",
-            )?;
-            let table_names = tbl_stmt
-                .query_map([], |row| row.get::<_, String>(0))?
-                .collect::<Result<Vec<_>, _>>()?;
+    // A session opened with --open-db comes first, as it does in queries
+    let session = db_args.open_db.as_ref().map(|path| InputFile {
+        path: path.clone(),
+        uri_params: None,
+        tables: None,
+    });
+    if let Some(session) = &session {
+        if !session.path.is_file() {
+            return Err(format!("File not found: {}", session.path.display()).into());
+        }
+    }
 
-            // 3) For each table, inline PRAGMA table_info
-            for table_name in table_names {
+    // Open SQLite files up front so we know which table names are ambiguous
+    let mut databases = Vec::new();
+    for file in session.iter().chain(files.iter().filter(|f| f.is_sqlite())) {
+        let conn = Connection::open(file.sqlite_uri(db_args.writable))?;
+        let table_names = list_sqlite_tables(&conn, "main", file)?;
+        databases.push((conn, table_names));
+    }
+    let csv_tables = files
+        .iter()
+        .filter(|f| !f.is_sqlite())
+        .map(|f| f.table_name())
+        .collect::<Vec<_>>();
+    let ambiguous = ambiguous_names(
+        databases
+            .iter()
+            .flat_map(|(_, tables)| tables.iter())
+            .chain(csv_tables.iter())
+            .chain(stdin_tables.iter().map(|(name, _)| name)),
+    );
+    let mut databases = databases.into_iter();
+    let print_database = |schema: &str, (conn, table_names): (Connection, Vec<String>)| {
+        for table_name in table_names {
+            if ambiguous.contains(&table_name.to_lowercase()) {
+                println!("Table: {}.{}", schema, table_name);
+            } else {
                 println!("Table: {}", table_name);
-
-                // PRAGMA cannot take parameters, so inline the table name
-                let pragma_sql = format!("PRAGMA table_info('{}')", table_name);
-                let mut col_stmt = conn.prepare(&pragma_sql)?;
-                let columns = col_stmt.query_map([], |row| {
-                    // row[1] = column name, row[2] = type
-                    Ok((row.get::<_, String>(1)?, row.get::<_, String>(2)?))
-                })?;
-
-                println!("Columns:");
-                for col in columns {
-                    let (name, typ) = col?;
-                    println!("  {} ({})", name, typ);
-                }
-                println!();
             }
+            print_sqlite_columns(&conn, &table_name)?;
+            println!();
+        }
+        Ok::<_, Box<dyn Error>>(())
+    };
+
+    if session.is_some() {
+        print_database(SESSION_SCHEMA, databases.next().unwrap())?;
+    }
+
+    // First show schemas for regular files
+    for file in files {
+        if file.is_sqlite() {
+            print_database(&file.table_name(), databases.next().unwrap())?;
         } else {
-            let table_name = file.file_stem().unwrap().to_string_lossy();
-            println!("Table: {}", table_name); 
+            let table_name = file.table_name();
+            println!("Table: {}", table_name);
 
             // For CSV files, use Polars to get schema with types
-            let df = CsvReader::from_path(file)?
-                .infer_schema(Some(100))
-                .has_header(true)
-                .finish()?;
-
-            println!("Columns:");
-            for (name, dtype) in df.schema().iter() {
-                let type_str = polars_to_sqlite_type(dtype);
-                println!("  {} ({})", name, type_str);
-            }
+            let options = csv_args.options_for(&table_name, Some(&file.path))?;
+            let table = read_csv(CsvSource::File(&file.path), &options)?;
+            print_csv_columns(&table, &options);
+            print_rejects_table(&table_name, &table, &options);
         }
         println!();
     }
@@ -227,21 +1124,26 @@ fn show_schemas(
         let mut buffer = Vec::new();
         io::stdin().read_to_end(&mut buffer)?;
 
-        if !buffer.is_empty() {
-            // Use Polars to infer schema from the buffer
-            let df = CsvReader::new(io::Cursor::new(&buffer))
-                .infer_schema(Some(100))
-                .has_header(true)
-                .finish()?;
-
-            // Show schema for each stdin table (they all share the same structure)
+        if sniff::detect(&buffer) == Format::Sqlite {
+            // Piped SQLite data holds tables of its own
+            let mut conn = Connection::open_in_memory()?;
+            conn.deserialize_read_exact(DatabaseName::Main, &buffer[..], buffer.len(), true)?;
+            for table_name in list_sqlite_tables(&conn, "main", &InputFile::stdin())? {
+                println!("Table: {}", table_name);
+                print_sqlite_columns(&conn, &table_name)?;
+                println!();
+            }
+        } else if !buffer.is_empty() {
+            // Show schema for each stdin table. They share the same data, but
+            // may be read with different options.
             for (table_name, _) in stdin_tables {
+                // Use Polars to infer schema from the buffer
+                let options = csv_args.options_for(table_name, None)?;
+                let table = read_csv(CsvSource::Memory(&buffer), &options)?;
+
                 println!("Table: {}", table_name);
-                println!("Columns:");
-                for (name, dtype) in df.schema().iter() {
-                    let type_str = polars_to_sqlite_type(dtype);
-                    println!("  {} ({})", name, type_str);
-                }
+                print_csv_columns(&table, &options);
+                print_rejects_table(table_name, &table, &options);
                 println!();
             }
         } else {
@@ -257,87 +1159,307 @@ fn show_schemas(
     Ok(())
 }
 
+// Print the columns of a table in a SQLite database
+fn print_sqlite_columns(conn: &Connection, table_name: &str) -> Result<(), Box<dyn Error>> {
+    // PRAGMA cannot take parameters, so inline the table name
+    let pragma_sql = format!("PRAGMA table_info({})", quote_identifier(table_name));
+    let mut col_stmt = conn.prepare(&pragma_sql)?;
+    let columns = col_stmt.query_map([], |row| {
+        // row[1] = column name, row[2] = type
+        Ok((row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+    })?;
+
+    println!("Columns:");
+    for col in columns {
+        let (name, typ) = col?;
+        println!("  {} ({})", name, typ);
+    }
+    Ok(())
+}
+
+// Print the columns of a loaded CSV table, marking overridden types and
+// showing the header in the file for renamed columns
+fn print_csv_columns(table: &CsvTable, options: &CsvOptions) {
+    println!("Columns:");
+    for ((name, dtype), header) in table.df.schema().iter().zip(&table.headers) {
+        let mut details = vec![polars_to_sqlite_type(dtype)];
+        if options.is_overridden(header) {
+            details.push("overridden".to_string());
+        }
+        if header != name.as_str() {
+            details.push(format!("header \"{}\"", header));
+        }
+        println!("  {} ({})", name, details.join(", "));
+    }
+}
+
+// Report malformed rows of a CSV table, and show the table they are put in
+fn print_rejects_table(table_name: &str, table: &CsvTable, options: &CsvOptions) {
+    report_rejects(table_name, &table.rejects, options);
+    if options.on_error == OnError::Quarantine {
+        println!();
+        println!("Table: {}", rejects_table_name(table_name));
+        println!("Columns:");
+        println!("  line (INTEGER)");
+        println!("  raw (TEXT)");
+        println!("  error (TEXT)");
+    }
+}
+
+// Run a query, or only load and save the inputs if there is none
 fn run_query(
-    query: &str,
-    files: &[PathBuf],
+    query: Option<&str>,
+    files: &[InputFile],
     stdin_tables: &[(String, String)],
     format: &str,
-    show_sql: bool,
+    verbose: bool,
+    db_args: &DbArgs,
+    csv_args: &CsvArgs,
 ) -> Result<(), Box<dyn Error>> {
-    let sql = compile_prql(query)?;
+    let query = query
+        .map(|query| compile_prql(query, &engine_options()))
+        .transpose()?;
+    let requested_indexes = db_args
+        .index
+        .iter()
+        .map(|arg| index::parse_key(arg))
+        .collect::<Result<Vec<_>, _>>()?;
 
-    if show_sql {
-        println!("{}", sql);
-        return Ok(());
+    // Saving a database needs all inputs, a query only the ones it reads
+    let needed = |table_name: &str| match &query {
+        Some(query) => db_args.save_db.is_some() || query.references(table_name),
+        None => true,
+    };
+    // Only the part of a CSV table the query can use is loaded, unless the
+    // tables are kept for later queries
+    let pushdown = |table_name: &str| match &query {
+        Some(query) if db_args.save_db.is_none() && !csv_args.cache => query.pushdown(table_name),
+        _ => None,
+    };
+
+    // Size of the CSV files to load, which decides where the database is kept
+    let mut input_size = 0;
+    if !csv_args.vtab {
+        for file in files {
+            if !file.is_sqlite() && needed(&file.table_name()) {
+                input_size += fs::metadata(&file.path)?.len();
+            }
+        }
+    }
+    let mut conn = db_args.open(input_size)?;
+
+    // Load regular files, remembering which tables each attached database
+    // exposes. CSV data that is read whole is collected and loaded below.
+    let mut loaded_tables = Vec::new();
+    let mut attached = Vec::new();
+    let mut csv_inputs = Vec::new();
+    // Files loaded with --cache that weren't cached yet
+    let mut uncached = Vec::new();
+
+    if let Some(path) = &db_args.open_db {
+        if !path.is_file() {
+            return Err(format!("File not found: {}", path.display()).into());
+        }
+        let session = InputFile {
+            path: path.clone(),
+            uri_params: None,
+            tables: None,
+        };
+        conn.execute(
+            "ATTACH DATABASE ?1 AS ?2",
+            [
+                session.sqlite_uri(db_args.writable),
+                SESSION_SCHEMA.to_string(),
+            ],
+        )?;
+        let tables = list_sqlite_tables(&conn, SESSION_SCHEMA, &session)?;
+        attached.push((SESSION_SCHEMA.to_string(), tables));
     }
 
-    let conn = Connection::open_in_memory()?;
-
-    // Load regular files
     for file in files {
-        let table_name = file.file_stem().unwrap().to_string_lossy();
+        let table_name = file.table_name();
 
-        if file
-            .extension()
-            .map(|e| e == "sqlite" || e == "db")
-            .unwrap_or(false)
-        {
+        if file.is_sqlite() {
             conn.execute(
-                &format!("ATTACH DATABASE '{}' AS '{}'", file.display(), table_name),
-                [],
+                "ATTACH DATABASE ?1 AS ?2",
+                [file.sqlite_uri(db_args.writable), table_name.clone()],
             )?;
-        } else {
-            load_csv_with_polars(&conn, &table_name.to_string(), file)?;
+            let tables = list_sqlite_tables(&conn, &table_name, file)?;
+            attached.push((table_name, tables));
+        } else if needed(&table_name) {
+            let options = csv_args.options_for(&table_name, Some(&file.path))?;
+            if options.on_error == OnError::Quarantine {
+                loaded_tables.push(rejects_table_name(&table_name));
+            }
+            loaded_tables.push(table_name.clone());
+
+            let cache_entry = if csv_args.cache {
+                Some(cache::Entry::new(&file.path, &options, csv_args.stream)?)
+            } else {
+                None
+            };
+            if let Some(entry) = cache_entry {
+                if entry.load(&conn, &table_name)? {
+                    continue;
+                }
+                uncached.push((entry, table_name.clone()));
+            }
+
+            // Only plain CSV files can be read in place or while loading
+            let plain_csv = sniff::detect_file(&file.path)? == Format::Csv;
+            if csv_args.vtab && plain_csv {
+                vtab::register(&conn, &table_name, &file.path, &options)?;
+            } else if csv_args.stream && plain_csv {
+                let input = fs::File::open(&file.path)?;
+                stream::load_csv_streaming(&conn, &table_name, input, &options)?;
+            } else {
+                let pushdown = pushdown(&table_name);
+                csv_inputs.push((table_name, CsvSource::File(&file.path), options, pushdown));
+            }
         }
     }
 
-    // Load stdin data if needed
-    if !stdin_tables.is_empty() && atty::isnt(atty::Stream::Stdin) {
+    // Load stdin data if the query needs it. A single stdin table of CSV data
+    // can be streamed, several need the data in memory. Piped SQLite data is
+    // attached like a SQLite file, whose tables the query may read.
+    let stdin_format = if !stdin_tables.is_empty() && atty::isnt(atty::Stream::Stdin) {
+        sniff::peek_stdin()?
+    } else {
+        None
+    };
+    let used_stdin_tables = stdin_tables
+        .iter()
+        .filter(|(table_name, _)| stdin_format == Some(Format::Sqlite) || needed(table_name))
+        .collect::<Vec<_>>();
+    let mut stdin_data = Vec::new();
+    if used_stdin_tables.len() == 1 && csv_args.stream && stdin_format == Some(Format::Csv) {
+        let table_name = &used_stdin_tables[0].0;
+        let options = csv_args.options_for(table_name, None)?;
+        stream::load_csv_streaming(&conn, table_name, io::stdin().lock(), &options)?;
+        if options.on_error == OnError::Quarantine {
+            loaded_tables.push(rejects_table_name(table_name));
+        }
+        loaded_tables.push(table_name.clone());
+    } else if !used_stdin_tables.is_empty() && atty::isnt(atty::Stream::Stdin) {
         // Read stdin data once into memory
-        let mut stdin_data = Vec::new();
         io::stdin().read_to_end(&mut stdin_data)?;
 
         if stdin_data.is_empty() {
             return Err("Stdin tables specified, but no data received from stdin".into());
         }
+    } else if !used_stdin_tables.is_empty() {
+        return Err("Stdin tables specified, but no data available from stdin".into());
+    }
 
-        // Create each requested table from the same stdin data
-        for (table_name, _) in stdin_tables {
-            load_csv_from_memory_with_polars(&conn, table_name, &stdin_data)?;
+    // Create each requested table from the same stdin data
+    if stdin_format == Some(Format::Sqlite) && !stdin_data.is_empty() {
+        for (table_name, _) in used_stdin_tables {
+            attach_sqlite_data(&mut conn, table_name, &stdin_data, db_args.writable)?;
+            let tables = list_sqlite_tables(&conn, table_name, &InputFile::stdin())?;
+            attached.push((table_name.clone(), tables));
+        }
+    } else if !stdin_data.is_empty() {
+        for (table_name, _) in used_stdin_tables {
+            let options = csv_args.options_for(table_name, None)?;
+            if options.on_error == OnError::Quarantine {
+                loaded_tables.push(rejects_table_name(table_name));
+            }
+            loaded_tables.push(table_name.clone());
+            csv_inputs.push((
+                table_name.clone(),
+                CsvSource::Memory(&stdin_data),
+                options,
+                pushdown(table_name),
+            ));
         }
-    } else if !stdin_tables.is_empty() {
-        return Err("Stdin tables specified, but no data available from stdin".into());
     }
+    load_csv_tables(&conn, &csv_inputs)?;
+
+    // A cache that can't be written shouldn't keep the query from running
+    for (entry, table_name) in &uncached {
+        if let Err(e) = entry.store(&conn, table_name) {
+            eprintln!("Warning: couldn't cache '{}': {}", table_name, e);
+        }
+    }
+
+    create_unqualified_views(&conn, &attached, &loaded_tables)?;
+
+    if let Some(path) = &db_args.save_db {
+        save_db(&conn, path, &loaded_tables, &attached)?;
+    }
+    let Some(query) = query else {
+        return Ok(());
+    };
+    index::create_indexes(&conn, &query.keys, &requested_indexes, verbose)?;
+
+    // Sums and averages of decimal columns, whether from --exact-decimals or
+    // --type, are computed exactly
+    decimal::register_aggregates(&conn)?;
+    let exact_rq = decimal::exact_aggregates(&query.rq, |schema, table, column| {
+        decimal::column_scale(&conn, schema, table, column)
+            .ok()
+            .flatten()
+    });
+    let sql = match exact_rq {
+        Some(rq) => prqlc::rq_to_sql(rq, &engine_options())?,
+        None => query.sql.clone(),
+    };
+    let denied = restrict_table_access(&conn, &attached, &loaded_tables)?;
 
     // Execute the query and format results
-    let mut stmt = conn.prepare(&sql)?;
+    let mut stmt = conn.prepare(&sql).map_err(|e| {
+        if let Some(reason) = denied.lock().unwrap().take() {
+            return reason.into();
+        }
+        let inputs = files
+            .iter()
+            .map(InputFile::table_name)
+            .chain(
+                stdin_tables
+                    .iter()
+                    .map(|(table_name, _)| table_name.clone()),
+            )
+            .collect::<Vec<_>>();
+        explain_missing_table(e, &inputs)
+    })?;
+
+    // Declared types of the result columns, which tell how to print booleans
+    // and decimals
+    let decl_types = stmt
+        .columns()
+        .iter()
+        .map(|c| c.decl_type().map(str::to_string))
+        .collect::<Vec<_>>();
 
     // Run query and immediately collect rows into a Vec to free up stmt
     let collected_rows = stmt
         .query_map([], |row| {
             Ok((0..row.as_ref().column_count())
-                .map(|i| match row.get_ref(i).unwrap() {
-                    rusqlite::types::ValueRef::Null => None,
-                    rusqlite::types::ValueRef::Integer(i) => Some(i.to_string()),
-                    rusqlite::types::ValueRef::Real(f) => Some(f.to_string()),
-                    rusqlite::types::ValueRef::Text(t) => {
-                        Some(String::from_utf8_lossy(t).to_string())
-                    }
-                    rusqlite::types::ValueRef::Blob(_) => Some("[BLOB]".to_string()),
+                .map(|i| {
+                    let value = row.get_ref(i).unwrap();
+                    format_value(value, decl_types[i].as_deref())
                 })
                 .collect::<Vec<Option<String>>>())
         })?
         .collect::<Result<Vec<_>, _>>()?;
 
     let column_names: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+    print_results(format, &column_names, &collected_rows)
+}
 
+/// Print query results in the output format
+fn print_results(
+    format: &str,
+    column_names: &[String],
+    collected_rows: &[Vec<Option<String>>],
+) -> Result<(), Box<dyn Error>> {
     // Output
     match format {
         "csv" => {
             // Print headers first
             println!("{}", column_names.join(","));
 
-            for row in &collected_rows {
+            for row in collected_rows {
                 let flat = row
                     .iter()
                     .map(|v| v.clone().unwrap_or_else(|| "NULL".into()))
@@ -346,46 +1468,19 @@ fn run_query(
             }
         }
         "jsonl" => {
-            for row in &collected_rows {
-                let json_obj: serde_json::Value = column_names
+            for row in collected_rows {
+                // Keys are sorted, even where a dependency makes serde_json
+                // keep them in insertion order
+                let json_obj = column_names
                     .iter()
                     .zip(row.iter())
-                    .map(|(k, v)| {
-                        (
-                            k.to_string(),
-                            match v {
-                                Some(val_str) => {
-                                    // Try to parse as number
-                                    if let Ok(int_val) = val_str.parse::<i64>() {
-                                        serde_json::Value::Number(int_val.into())
-                                    } else if let Ok(float_val) = val_str.parse::<f64>() {
-                                        // Create number from float (with some safeguards)
-                                        match serde_json::Number::from_f64(float_val) {
-                                            Some(num) => serde_json::Value::Number(num),
-                                            None => serde_json::Value::String(val_str.clone()),
-                                        }
-                                    } else if val_str == "true" {
-                                        serde_json::Value::Bool(true)
-                                    } else if val_str == "false" {
-                                        serde_json::Value::Bool(false)
-                                    } else if val_str == "null" {
-                                        serde_json::Value::Null
-                                    } else {
-                                        // Default to string for everything else
-                                        serde_json::Value::String(val_str.clone())
-                                    }
-                                }
-                                None => serde_json::Value::Null,
-                            },
-                        )
-                    })
-                    .collect::<serde_json::Map<_, _>>()
-                    .into();
+                    .map(|(k, v)| Ok((k.as_str(), json_value(v.as_deref())?)))
+                    .collect::<serde_json::Result<std::collections::BTreeMap<_, _>>>()?;
                 println!("{}", serde_json::to_string(&json_obj)?);
             }
         }
         "logfmt" => {
-            for row in &collected_rows {
+            for row in collected_rows {
                 let mut line = String::new();
                 for (k, v) in column_names.iter().zip(row.iter()) {
                     let val = v.clone().unwrap_or_else(|| "NULL".to_string());
@@ -394,21 +1489,296 @@ fn run_query(
                 println!("{}", line.trim_end());
             }
         }
-        "table" | _ => {
-            print_table(&column_names, &collected_rows);
+        _ => {
+            print_table(column_names, collected_rows);
         }
     }
 
     Ok(())
 }
 
-fn compile_prql(query: &str) -> Result<String, Box<dyn Error>> {
-    if query.ends_with(".prql") && std::path::Path::new(query).exists() {
-        let prql = fs::read_to_string(query)?;
-        Ok(prqlc::compile(&prql, &prqlc::Options::default())?)
+// JSON for a result value. Decimal numbers are written as they are printed,
+// so 12.50 keeps its scale.
+fn json_value(value: Option<&str>) -> serde_json::Result<Box<RawValue>> {
+    let value = match value {
+        Some(val_str) => {
+            if val_str.contains('.') && val_str.parse::<f64>().is_ok() {
+                if let Ok(number) = RawValue::from_string(val_str.to_string()) {
+                    return Ok(number);
+                }
+            }
+            // Try to parse as number
+            if let Ok(int_val) = val_str.parse::<i64>() {
+                serde_json::Value::Number(int_val.into())
+            } else if let Ok(float_val) = val_str.parse::<f64>() {
+                // Create number from float (with some safeguards)
+                match serde_json::Number::from_f64(float_val) {
+                    Some(num) => serde_json::Value::Number(num),
+                    None => serde_json::Value::String(val_str.to_string()),
+                }
+            } else if val_str == "true" {
+                serde_json::Value::Bool(true)
+            } else if val_str == "false" {
+                serde_json::Value::Bool(false)
+            } else if val_str == "null" {
+                serde_json::Value::Null
+            } else {
+                // Default to string for everything else
+                serde_json::Value::String(val_str.to_string())
+            }
+        }
+        None => serde_json::Value::Null,
+    };
+    serde_json::value::to_raw_value(&value)
+}
+
+// Attach SQLite data read from stdin as the database `schema`. It's kept in
+// memory, so with `writable` changes are possible, but not saved anywhere.
+fn attach_sqlite_data(
+    conn: &mut Connection,
+    schema: &str,
+    data: &[u8],
+    writable: bool,
+) -> Result<(), Box<dyn Error>> {
+    conn.execute("ATTACH DATABASE ':memory:' AS ?1", [schema])?;
+    conn.deserialize_read_exact(DatabaseName::Attached(schema), data, data.len(), !writable)?;
+    Ok(())
+}
+
+// Make tables of attached SQLite databases addressable without the database
+// prefix (`from employees` instead of `from company.employees`). Names that
+// occur in more than one input stay qualified-only: SQLite would resolve them
+// to the first database that has them, so they get a view as well, which
+// restrict_table_access doesn't let queries read. Loaded CSV tables keep their
+// plain names.
+fn create_unqualified_views(
+    conn: &Connection,
+    attached: &[(String, Vec<String>)],
+    loaded_tables: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let ambiguous = ambiguous_names(
+        attached
+            .iter()
+            .flat_map(|(_, tables)| tables.iter())
+            .chain(loaded_tables.iter()),
+    );
+
+    let mut created = Vec::new();
+    for (schema, tables) in attached {
+        for table in tables {
+            let name = table.to_lowercase();
+            if ambiguous.contains(&name)
+                && (loaded_tables.iter().any(|t| t.eq_ignore_ascii_case(table))
+                    || created.contains(&name))
+            {
+                continue;
+            }
+            created.push(name);
+            conn.execute(
+                &format!(
+                    "CREATE TEMP VIEW {} AS SELECT * FROM {}.{}",
+                    quote_identifier(table),
+                    quote_identifier(schema),
+                    quote_identifier(table)
+                ),
+                [],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+// Keep queries from reading tables of attached SQLite databases that weren't
+// selected with `db.sqlite:table1,table2`, and from using the plain name of a
+// table that several inputs have. SQLite finds both by their plain name in any
+// attached database, so reads are checked while the query is prepared. The
+// returned slot tells why a read was denied.
+fn restrict_table_access(
+    conn: &Connection,
+    attached: &[(String, Vec<String>)],
+    loaded_tables: &[String],
+) -> Result<Arc<Mutex<Option<String>>>, Box<dyn Error>> {
+    let mut hidden = HashSet::new();
+    for (schema, tables) in attached {
+        for table in list_sqlite_tables(conn, schema, &InputFile::stdin())? {
+            if !tables.iter().any(|t| t.eq_ignore_ascii_case(&table)) {
+                hidden.insert((schema.to_lowercase(), table.to_lowercase()));
+            }
+        }
+    }
+    // Ambiguous names mapped to a database that has them, for the error
+    let ambiguous = ambiguous_names(
+        attached
+            .iter()
+            .flat_map(|(_, tables)| tables.iter())
+            .chain(loaded_tables.iter()),
+    )
+    .into_iter()
+    .filter(|name| !loaded_tables.iter().any(|t| t.eq_ignore_ascii_case(name)))
+    .filter_map(|name| {
+        let (schema, _) = attached
+            .iter()
+            .find(|(_, tables)| tables.iter().any(|t| t.eq_ignore_ascii_case(&name)))?;
+        Some((name, schema.clone()))
+    })
+    .collect::<HashMap<_, _>>();
+
+    let denied = Arc::new(Mutex::new(None));
+    let reason = Arc::clone(&denied);
+    conn.authorizer(Some(move |context: AuthContext| {
+        let AuthAction::Read { table_name, .. } = context.action else {
+            return Authorization::Allow;
+        };
+        let schema = context.database_name.unwrap_or_default().to_lowercase();
+        let table = table_name.to_lowercase();
+        let message = if hidden.contains(&(schema.clone(), table.clone())) {
+            format!(
+                "Table '{}.{}' wasn't selected on the command line",
+                schema, table_name
+            )
+        } else if let Some(owner) = ambiguous
+            .get(&table)
+            .filter(|_| schema == "temp" && context.accessor.is_none())
+        {
+            format!(
+                "Table '{}' is in several inputs; qualify it with the file name, e.g. 'from {}.{}'",
+                table_name, owner, table_name
+            )
+        } else {
+            return Authorization::Allow;
+        };
+        *reason.lock().unwrap() = Some(message);
+        Authorization::Deny
+    }));
+    Ok(denied)
+}
+
+// Text for a result value, None for NULL. Columns declared as BOOLEAN print
+// true/false, DECIMAL(p,s) columns print with their scale.
+fn format_value(value: ValueRef, decl_type: Option<&str>) -> Option<String> {
+    let decl_type = decl_type.unwrap_or_default();
+    let scaled =
+        decimal::declared_scale(decl_type).and_then(|s| decimal::format_with_scale(value, s));
+    if scaled.is_some() {
+        return scaled;
+    }
+    match value {
+        ValueRef::Integer(b @ (0 | 1)) if is_boolean_type(decl_type) => Some((b == 1).to_string()),
+        ValueRef::Null => None,
+        ValueRef::Integer(i) => Some(i.to_string()),
+        ValueRef::Real(f) => Some(f.to_string()),
+        ValueRef::Text(t) => Some(String::from_utf8_lossy(t).to_string()),
+        ValueRef::Blob(_) => Some("[BLOB]".to_string()),
+    }
+}
+
+fn is_boolean_type(decl_type: &str) -> bool {
+    decl_type.eq_ignore_ascii_case("BOOLEAN") || decl_type.eq_ignore_ascii_case("BOOL")
+}
+
+// Name the input tables when the query reads from a table that doesn't exist
+fn explain_missing_table(error: rusqlite::Error, inputs: &[String]) -> Box<dyn Error> {
+    if let rusqlite::Error::SqliteFailure(_, Some(message)) = &error {
+        if let Some(table) = message.strip_prefix("no such table: ") {
+            return format!(
+                "Table '{}' not found. Input tables: {}",
+                table,
+                inputs.join(", ")
+            )
+            .into();
+        }
+    }
+    error.into()
+}
+
+/// A PRQL query compiled to SQL
+struct CompiledQuery {
+    sql: String,
+    /// Parts of the table names the query reads from, e.g. "db" and "users"
+    /// for `from db.users`. None if the query has s-strings, whose SQL can
+    /// read from any table.
+    tables: Option<Vec<String>>,
+    /// Columns the query looks rows up by, which are worth indexing
+    keys: Vec<index::Key>,
+    /// What the query reads from each input table, by lowercased name
+    pushdowns: HashMap<String, pushdown::Pushdown>,
+    /// The query's relational form, from which the SQL is generated again
+    /// when aggregates are replaced
+    rq: prqlc::ir::rq::RelationalQuery,
+}
+
+impl CompiledQuery {
+    /// Whether the query may read from a CSV table or its rejects table
+    fn references(&self, table_name: &str) -> bool {
+        let Some(tables) = &self.tables else {
+            return true;
+        };
+        let rejects = rejects_table_name(table_name);
+        tables
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(table_name) || t.eq_ignore_ascii_case(&rejects))
+    }
+
+    /// The columns and rows of a CSV table the query can use. None if that
+    /// can't be told, e.g. because the query has s-strings.
+    fn pushdown(&self, table_name: &str) -> Option<&pushdown::Pushdown> {
+        self.tables.as_ref()?;
+        self.pushdowns.get(&table_name.to_lowercase())
+    }
+}
+
+// Compiler options for a query that is run. A target in the query header is
+// ignored, as SQLite and Polars both need generic SQL.
+fn engine_options() -> prqlc::Options {
+    prqlc::Options::default().with_target(prqlc::Target::Sql(Some(prqlc::sql::Dialect::Generic)))
+}
+
+fn compile_prql(query: &str, options: &prqlc::Options) -> Result<CompiledQuery, Box<dyn Error>> {
+    let prql = if query.ends_with(".prql") && std::path::Path::new(query).exists() {
+        fs::read_to_string(query)?
     } else {
-        Ok(prqlc::compile(query, &prqlc::Options::default())?)
+        query.to_string()
+    };
+    let sql = prqlc::compile(&prql, options)?;
+
+    // The query compiled, so it can be lowered again to find its tables
+    let rq = prqlc::prql_to_pl(&prql).and_then(prqlc::pl_to_rq)?;
+    Ok(CompiledQuery {
+        sql,
+        tables: referenced_tables(&rq),
+        keys: index::query_keys(&rq),
+        pushdowns: pushdown::query_pushdowns(&rq),
+        rq,
+    })
+}
+
+fn referenced_tables(rq: &prqlc::ir::rq::RelationalQuery) -> Option<Vec<String>> {
+    fn has_sstring(value: &serde_json::Value) -> bool {
+        match value {
+            serde_json::Value::Object(map) => map
+                .iter()
+                .any(|(key, value)| key == "SString" || has_sstring(value)),
+            serde_json::Value::Array(values) => values.iter().any(has_sstring),
+            _ => false,
+        }
+    }
+    if has_sstring(&serde_json::to_value(rq).ok()?) {
+        return None;
     }
+
+    let tables = rq
+        .tables
+        .iter()
+        .filter_map(|table| match &table.relation.kind {
+            prqlc::ir::rq::RelationKind::ExternRef(prqlc::ir::pl::TableExternRef::LocalTable(
+                ident,
+            )) => Some(ident),
+            _ => None,
+        })
+        .flat_map(|ident| ident.path.iter().chain([&ident.name]).cloned())
+        .collect();
+    Some(tables)
 }
 
 fn print_table(headers: &[String], rows: &[Vec<Option<String>>]) {
@@ -449,131 +1819,1283 @@ fn print_table(headers: &[String], rows: &[Vec<Option<String>>]) {
     }
 }
 
-// Helper function to safely convert Polars AnyValue to SQLite value
-fn convert_any_value_to_sql(value: AnyValue) -> Box<dyn ToSql> {
-    match value {
-        AnyValue::Null => Box::new(Option::<String>::None),
-        AnyValue::Int8(v) => Box::new(v as i64),
-        AnyValue::Int16(v) => Box::new(v as i64),
-        AnyValue::Int32(v) => Box::new(v as i64),
-        AnyValue::Int64(v) => Box::new(v),
-        AnyValue::UInt8(v) => Box::new(v as i64),
-        AnyValue::UInt16(v) => Box::new(v as i64),
-        AnyValue::UInt32(v) => Box::new(v as i64),
-        AnyValue::UInt64(v) => Box::new(v as i64),
-        AnyValue::Float32(v) => Box::new(v as f64),
-        AnyValue::Float64(v) => Box::new(v),
-        AnyValue::Boolean(v) => Box::new(if v { 1i64 } else { 0i64 }),
-        AnyValue::String(v) => Box::new(v.to_string()),
+// Helper function to safely convert Polars AnyValue to SQLite value.
+// Strings matching one of the `null_values` markers become NULL.
+fn convert_any_value_to_sql<'a>(value: AnyValue<'a>, null_values: &[String]) -> ToSqlOutput<'a> {
+    let value = match value {
+        AnyValue::Null => Value::Null,
+        AnyValue::String(v) if null_values.iter().any(|n| n == v) => Value::Null,
+        // Text and blobs are passed to SQLite without copying
+        AnyValue::String(v) => return ToSqlOutput::Borrowed(ValueRef::Text(v.as_bytes())),
+        AnyValue::Binary(v) => return ToSqlOutput::Borrowed(ValueRef::Blob(v)),
+        AnyValue::Int8(v) => Value::Integer(v as i64),
+        AnyValue::Int16(v) => Value::Integer(v as i64),
+        AnyValue::Int32(v) => Value::Integer(v as i64),
+        AnyValue::Int64(v) => Value::Integer(v),
+        AnyValue::UInt8(v) => Value::Integer(v as i64),
+        AnyValue::UInt16(v) => Value::Integer(v as i64),
+        AnyValue::UInt32(v) => Value::Integer(v as i64),
+        // Values beyond SQLite's INTEGER range are kept as text instead of wrapping
+        AnyValue::UInt64(v) => match i64::try_from(v) {
+            Ok(v) => Value::Integer(v),
+            Err(_) => Value::Text(v.to_string()),
+        },
+        AnyValue::Float32(v) => Value::Real(v as f64),
+        AnyValue::Float64(v) => Value::Real(v),
+        AnyValue::Boolean(v) => Value::Integer(v as i64),
+        AnyValue::Date(days) => Value::Text(dates::format_date(days)),
+        AnyValue::Datetime(v, unit, _) => Value::Text(dates::format_datetime(v, unit)),
+        AnyValue::Decimal(v, scale) => Value::Real(decimal::Decimal::new(v, scale as u32).to_f64()),
+        AnyValue::List(_) | AnyValue::Struct(..) | AnyValue::StructOwned(_) => {
+            Value::Text(any_value_to_json(value).to_string())
+        }
         // Convert other types to strings
-        _ => Box::new(value.to_string()),
+        _ => Value::Text(value.to_string()),
+    };
+    ToSqlOutput::Owned(value)
+}
+
+// Convert a Polars value to JSON, so that nested values can be used with
+// SQLite's JSON functions like json_extract
+fn any_value_to_json(value: AnyValue) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        AnyValue::Null => Value::Null,
+        AnyValue::Boolean(v) => Value::Bool(v),
+        AnyValue::Int8(v) => v.into(),
+        AnyValue::Int16(v) => v.into(),
+        AnyValue::Int32(v) => v.into(),
+        AnyValue::Int64(v) => v.into(),
+        AnyValue::UInt8(v) => v.into(),
+        AnyValue::UInt16(v) => v.into(),
+        AnyValue::UInt32(v) => v.into(),
+        AnyValue::UInt64(v) => v.into(),
+        AnyValue::Float32(v) => {
+            serde_json::Number::from_f64(v as f64).map_or(Value::Null, Value::Number)
+        }
+        AnyValue::Float64(v) => serde_json::Number::from_f64(v).map_or(Value::Null, Value::Number),
+        AnyValue::String(v) => Value::String(v.to_string()),
+        AnyValue::Date(days) => Value::String(dates::format_date(days)),
+        AnyValue::Datetime(v, unit, _) => Value::String(dates::format_datetime(v, unit)),
+        AnyValue::List(series) => Value::Array(series.iter().map(any_value_to_json).collect()),
+        AnyValue::Struct(..) | AnyValue::StructOwned(_) => {
+            let Ok(AnyValue::StructOwned(payload)) = value.into_static() else {
+                return Value::Null;
+            };
+            let (values, fields) = *payload;
+            fields
+                .iter()
+                .zip(values)
+                .map(|(field, v)| (field.name().to_string(), any_value_to_json(v)))
+                .collect::<serde_json::Map<_, _>>()
+                .into()
+        }
+        _ => Value::String(value.to_string()),
     }
 }
 
-// New function to load CSV using Polars with type inference
-fn load_csv_with_polars(
-    conn: &Connection,
-    table_name: &str,
-    path: &PathBuf,
-) -> Result<(), Box<dyn Error>> {
-    // Use Polars to read the CSV with type inference
-    let df = CsvReader::from_path(path)?
-        .infer_schema(Some(100))
-        .has_header(true)
-        .finish()?;
+#[derive(Clone, Copy)]
+enum CsvSource<'a> {
+    File(&'a Path),
+    Memory(&'a [u8]),
+}
 
-    // Create table with appropriate column types
-    let mut create_table_sql = format!("CREATE TABLE '{}' (", table_name);
-    let columns = df
+/// A CSV table read into a DataFrame
+struct CsvTable {
+    df: DataFrame,
+    /// Header in the file for each column
+    headers: Vec<String>,
+    /// Malformed rows left out with --on-error skip or quarantine
+    rejects: Vec<RejectedRow>,
+}
+
+struct RejectedRow {
+    /// 1-based line number in the file
+    line: u64,
+    raw: String,
+    error: String,
+}
+
+// Read CSV data into a DataFrame using Polars with type inference
+fn read_csv(source: CsvSource, options: &CsvOptions) -> Result<CsvTable, Box<dyn Error>> {
+    read_csv_for_query(source, options, None)
+}
+
+// Read CSV data, parsing only the columns, and for a plain CSV file only the
+// rows, that the query can use. The pushdown is still to be applied to the
+// result.
+fn read_csv_for_query(
+    source: CsvSource,
+    options: &CsvOptions,
+    pushdown: Option<&pushdown::Pushdown>,
+) -> Result<CsvTable, Box<dyn Error>> {
+    // Data in other formats is recognized by its content
+    let format = match source {
+        CsvSource::File(path) => sniff::detect_file(path)?,
+        CsvSource::Memory(data) => sniff::detect(data),
+    };
+    if format != Format::Csv {
+        return read_other_format(source, format, options, pushdown);
+    }
+
+    if let (CsvSource::File(path), Some(pushdown)) = (source, pushdown) {
+        if let Some(scan) = scan_csv(path, options)? {
+            // Values that don't fit the inferred types are left to the
+            // fallback of the eager reader
+            if let Ok(df) = pushdown.scan(scan.frame).and_then(LazyFrame::collect) {
+                let headers = df
+                    .get_column_names()
+                    .iter()
+                    .map(|name| {
+                        scan.headers[scan.names.iter().position(|n| n == name).unwrap()].clone()
+                    })
+                    .collect();
+                return Ok(CsvTable {
+                    df,
+                    headers,
+                    rejects: Vec::new(),
+                });
+            }
+        }
+    }
+
+    let mut rejects = Vec::new();
+    let mut df = match source {
+        CsvSource::File(path) if options.skip_footer == 0 && options.on_error == OnError::Fail => {
+            finish_csv_reader(|| CsvReader::from_path(path), options, pushdown)?
+        }
+        CsvSource::File(path) => {
+            // The footer can only be found by looking at the end of the data,
+            // and malformed rows by looking at every row
+            let data = fs::read(path)?;
+            return read_csv_for_query(CsvSource::Memory(&data), options, pushdown);
+        }
+        CsvSource::Memory(data) => {
+            let data = strip_footer(data, options.skip_footer);
+            if options.on_error == OnError::Fail {
+                let new_reader = || Ok(CsvReader::new(io::Cursor::new(data)));
+                finish_csv_reader(new_reader, options, pushdown)?
+            } else {
+                let (data, bad_rows) = split_bad_rows(data, options)?;
+                rejects = bad_rows;
+                let new_reader = || Ok(CsvReader::new(io::Cursor::new(&data)));
+                finish_csv_reader(new_reader, options, pushdown)?
+            }
+        }
+    };
+
+    normalize_date_columns(&mut df, options)?;
+    fit_unsigned_columns(&mut df)?;
+
+    let headers = name_columns(&mut df, options)?;
+    Ok(CsvTable {
+        df,
+        headers,
+        rejects,
+    })
+}
+
+// Read gzip-compressed, JSON or Parquet data into a DataFrame. Compressed
+// data is read like any other input once unpacked. Of the CSV options, only
+// those about columns apply.
+fn read_other_format(
+    source: CsvSource,
+    format: Format,
+    options: &CsvOptions,
+    pushdown: Option<&pushdown::Pushdown>,
+) -> Result<CsvTable, Box<dyn Error>> {
+    let file_data;
+    let data = match source {
+        CsvSource::File(path) => {
+            file_data = fs::read(path)?;
+            &file_data[..]
+        }
+        CsvSource::Memory(data) => data,
+    };
+
+    let mut df = match format {
+        Format::Gzip => {
+            let mut unpacked = Vec::new();
+            MultiGzDecoder::new(data).read_to_end(&mut unpacked)?;
+            return read_csv_for_query(CsvSource::Memory(&unpacked), options, pushdown);
+        }
+        Format::Json => {
+            // An array of objects, or one object per line
+            let json_format = match data.iter().find(|b| !b.is_ascii_whitespace()) {
+                Some(b'[') => JsonFormat::Json,
+                _ => JsonFormat::JsonLines,
+            };
+            JsonReader::new(io::Cursor::new(data))
+                .with_json_format(json_format)
+                .infer_schema_len(options.infer_rows.filter(|&n| n > 0))
+                .finish()?
+        }
+        Format::Parquet => ParquetReader::new(io::Cursor::new(data)).finish()?,
+        Format::Sqlite => return Err("SQLite data can't be loaded as a table".into()),
+        Format::Csv => unreachable!("CSV data is read by read_csv"),
+    };
+
+    // Dates are parsed by normalize_date_columns, other overrides are casts
+    for (header, dtype) in &options.column_types {
+        if matches!(dtype, DataType::Date | DataType::Datetime(..)) {
+            continue;
+        }
+        let Ok(column) = df.column(header) else {
+            continue;
+        };
+        let cast = column.strict_cast(dtype).map_err(|_| {
+            format!(
+                "Column '{}' can't be read as {}",
+                header,
+                polars_to_sqlite_type(dtype)
+            )
+        })?;
+        df.replace(header, cast)?;
+    }
+
+    normalize_date_columns(&mut df, options)?;
+    fit_unsigned_columns(&mut df)?;
+
+    let headers = name_columns(&mut df, options)?;
+    Ok(CsvTable {
+        df,
+        headers,
+        rejects: Vec::new(),
+    })
+}
+
+// Separate malformed rows from CSV data. Returns the data without them, and
+// the rows with their line numbers and what's wrong with them. A row is
+// malformed if it has a different number of fields than the header, e.g.
+// because of an unterminated quote, or isn't valid UTF-8.
+fn split_bad_rows(
+    data: &[u8],
+    options: &CsvOptions,
+) -> Result<(Vec<u8>, Vec<RejectedRow>), Box<dyn Error>> {
+    // Lines before the header are kept for the CSV reader to skip
+    let mut preamble_end = 0;
+    for _ in 0..options.skip_rows {
+        match data[preamble_end..].iter().position(|&b| b == b'\n') {
+            Some(i) => preamble_end += i + 1,
+            None => preamble_end = data.len(),
+        }
+    }
+    let (preamble, rows) = data.split_at(preamble_end);
+    let preamble_lines = preamble.iter().filter(|&&b| b == b'\n').count() as u64;
+
+    let comment = options.comment_char.as_ref().map(|c| c.as_bytes()[0]);
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .comment(comment)
+        .from_reader(rows);
+    let mut clean = preamble.to_vec();
+    let mut rejects = Vec::new();
+    let mut header_fields = None;
+    let mut record = csv::ByteRecord::new();
+    loop {
+        let (mut start, mut line) = (reader.position().byte() as usize, reader.position().line());
+        let result = reader.read_byte_record(&mut record);
+        let end = reader.position().byte() as usize;
+
+        // The reader also consumed the blank and comment lines before the record
+        while let Some(i) = rows[start..end].iter().position(|&b| b == b'\n') {
+            let first_line = rows[start..start + i].trim_ascii();
+            if !(first_line.is_empty() || comment.is_some_and(|c| first_line.first() == Some(&c))) {
+                break;
+            }
+            start += i + 1;
+            line += 1;
+        }
+        let raw = &rows[start..end];
+
+        let error = match result {
+            Ok(false) => break,
+            Ok(true) => match header_fields {
+                None => {
+                    header_fields = Some(record.len());
+                    None
+                }
+                Some(n) if record.len() != n => {
+                    Some(format!("expected {} fields, found {}", n, record.len()))
+                }
+                Some(_) => std::str::from_utf8(raw)
+                    .err()
+                    .map(|_| "invalid UTF-8".to_string()),
+            },
+            Err(err) => return Err(err.into()),
+        };
+
+        match error {
+            None => clean.extend_from_slice(raw),
+            Some(error) => rejects.push(RejectedRow {
+                line: preamble_lines + line,
+                raw: String::from_utf8_lossy(raw)
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string(),
+                error,
+            }),
+        }
+    }
+    Ok((clean, rejects))
+}
+
+// Give the columns their final names: renamed by the sidecar file or
+// normalized, with empty names replaced by column_N and duplicates numbered
+// (notes, notes_2). Returns the original headers.
+fn name_columns(df: &mut DataFrame, options: &CsvOptions) -> Result<Vec<String>, Box<dyn Error>> {
+    let (headers, names) = column_names(&df.get_column_names(), options);
+    df.set_column_names(&names)?;
+    Ok(headers)
+}
+
+// The headers and table column names for the columns Polars read
+fn column_names(read_names: &[&str], options: &CsvOptions) -> (Vec<String>, Vec<String>) {
+    // Polars names a repeated header "notes_duplicated_0"
+    let mut headers: Vec<String> = Vec::with_capacity(read_names.len());
+    for &name in read_names {
+        let header = name
+            .rsplit_once("_duplicated_")
+            .filter(|(base, n)| {
+                n.parse::<usize>().is_ok() && headers.iter().any(|h| h.as_str() == *base)
+            })
+            .map_or(name, |(base, _)| base);
+        headers.push(header.to_string());
+    }
+
+    // SQLite column names are case-insensitive
+    let mut names: Vec<String> = Vec::with_capacity(headers.len());
+    for (i, header) in headers.iter().enumerate() {
+        // A sidecar rename applies to the first column with the header
+        let first = headers.iter().position(|h| h == header) == Some(i);
+        let renamed = options
+            .column_renames
+            .iter()
+            .find(|(h, _)| h == header)
+            .filter(|_| first);
+        let mut name = match renamed {
+            Some((_, name)) => name.clone(),
+            None => options.normalize_columns.apply(header),
+        };
+        if name.trim().is_empty() {
+            name = format!("column_{}", i + 1);
+        }
+        let base = name.clone();
+        let mut n = 2;
+        while names.iter().any(|other| other.eq_ignore_ascii_case(&name)) {
+            name = format!("{}_{}", base, n);
+            n += 1;
+        }
+        names.push(name);
+    }
+    (headers, names)
+}
+
+// SQLite's INTEGER is a signed 64-bit integer. Unsigned columns with larger
+// values are loaded as TEXT rather than losing digits.
+fn fit_unsigned_columns(df: &mut DataFrame) -> Result<(), Box<dyn Error>> {
+    let names = df
         .schema()
         .iter()
-        .map(|(name, dtype)| {
-            let sqlite_type = polars_to_sqlite_type(dtype);
-            format!("'{}' {}", name, sqlite_type)
-        })
-        .collect::<Vec<_>>()
-        .join(", ");
+        .filter(|(_, dtype)| **dtype == DataType::UInt64)
+        .map(|(name, _)| name.to_string())
+        .collect::<Vec<_>>();
 
-    create_table_sql.push_str(&columns);
-    create_table_sql.push_str(")");
+    for name in names {
+        let values = df.column(&name)?.u64()?;
+        if values.max().is_some_and(|max| max > i64::MAX as u64) {
+            eprintln!(
+                "Warning: column '{}' has values beyond the INTEGER range; loading it as TEXT",
+                name
+            );
+            let text = df.column(&name)?.cast(&DataType::String)?;
+            df.replace(&name, text)?;
+        }
+    }
+    Ok(())
+}
 
-    conn.execute(&create_table_sql, [])?;
+// Convert columns holding dates or timestamps to Date and Datetime columns,
+// which are stored in a canonical ISO-8601 form. Columns with a date format
+// or a DATE/DATETIME type given by the user must convert, others are detected.
+fn normalize_date_columns(df: &mut DataFrame, options: &CsvOptions) -> Result<(), Box<dyn Error>> {
+    let names = df
+        .get_column_names()
+        .iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>();
 
-    // Prepare placeholders for the insert statement
-    let placeholders = vec!["?"; df.width()].join(", ");
-    let insert_sql = format!("INSERT INTO '{}' VALUES ({})", table_name, placeholders);
+    for name in names {
+        let column_format = options
+            .column_date_formats
+            .iter()
+            .find(|(c, _)| *c == name)
+            .map(|(_, f)| f);
+        let type_override = options
+            .column_types
+            .iter()
+            .find(|(c, _)| *c == name)
+            .map(|(_, t)| t);
+        let date_type =
+            type_override.filter(|t| matches!(t, DataType::Date | DataType::Datetime(..)));
+        let required = column_format.is_some() || date_type.is_some();
+
+        let series = df.column(&name)?;
+        if !required && (type_override.is_some() || *series.dtype() != DataType::String) {
+            continue;
+        }
+
+        match dates::detect(series, column_format.or(options.date_format.as_ref()))? {
+            Detection::Dates(dates, _) => {
+                let dates = match date_type {
+                    Some(dtype) => dates.cast(dtype)?,
+                    None => dates,
+                };
+                df.replace(&name, dates)?;
+            }
+            Detection::Ambiguous if required => {
+                return Err(format!(
+                    "Column '{}' could hold day-first or month-first dates; use --date-format to choose",
+                    name
+                )
+                .into());
+            }
+            Detection::Ambiguous => eprintln!(
+                "Warning: column '{}' could hold day-first or month-first dates; loading it as TEXT (use --date-format to choose)",
+                name
+            ),
+            Detection::NotDates { row, value } if required => {
+                return Err(format!(
+                    "Column '{}' can't be read as a date: invalid value \"{}\" in data row {}",
+                    name,
+                    value,
+                    row + 1
+                )
+                .into());
+            }
+            Detection::NotDates { .. } => {}
+        }
+    }
+
+    Ok(())
+}
 
-    // Insert data row by row without using a prepared statement
-    for row_idx in 0..df.height() {
-        let mut params: Vec<Box<dyn ToSql>> = Vec::with_capacity(df.width());
+// Run a CSV reader created by `new_reader` with the configured options
+fn finish_csv_reader<'a, R: MmapBytesReader + 'a>(
+    new_reader: impl Fn() -> PolarsResult<CsvReader<'a, R>>,
+    options: &CsvOptions,
+    pushdown: Option<&pushdown::Pushdown>,
+) -> Result<DataFrame, Box<dyn Error>> {
+    // Only the columns the query uses are parsed, unless leaving out the
+    // others would change how the used ones are named
+    let projection = match pushdown {
+        Some(pushdown) => {
+            let schema = inferred_schema(configure_csv_reader(new_reader()?, options))?;
+            let read_names = schema.iter_names().map(|n| n.as_str()).collect::<Vec<_>>();
+            let (_, names) = column_names(&read_names, options);
+            pushdown.projection(&names).filter(|columns| {
+                let projected = columns.iter().map(|&i| read_names[i]).collect::<Vec<_>>();
+                let (_, projected_names) = column_names(&projected, options);
+                columns
+                    .iter()
+                    .zip(&projected_names)
+                    .all(|(&i, name)| names[i] == *name)
+            })
+        }
+        None => None,
+    };
 
-        for col_idx in 0..df.width() {
-            let series = &df.get_columns()[col_idx];
-            let value = series.get(row_idx);
-            match value {
-                Ok(any_value) => params.push(convert_any_value_to_sql(any_value)),
-                Err(_) => params.push(Box::new(Option::<String>::None)),
+    let mut decimal_columns = Vec::new();
+    let overrides = if options.column_types.is_empty() && !options.exact_decimals {
+        None
+    } else {
+        // Polars matches overrides to headers, but falls back to matching by
+        // position if there are as many overrides as columns. Only pass
+        // overrides for headers that exist to rule that out.
+        let headers = inferred_schema(configure_csv_reader(new_reader()?, options))?;
+        let mut overrides = options
+            .column_types
+            .iter()
+            .filter(|(header, _)| headers.contains(header))
+            .map(|(header, dtype)| match dtype {
+                // Dates are parsed by normalize_date_columns
+                DataType::Date | DataType::Datetime(..) => Field::new(header, DataType::String),
+                // Decimals are parsed by convert_decimal_columns
+                DataType::Decimal(..) => {
+                    decimal_columns.push(header.clone());
+                    Field::new(header, DataType::String)
+                }
+                _ => Field::new(header, dtype.clone()),
+            })
+            .collect::<Schema>();
+
+        // Read would-be REAL columns as text to keep their exact digits
+        if options.exact_decimals {
+            for (header, dtype) in headers.iter() {
+                if *dtype == DataType::Float64 && !options.is_overridden(header) {
+                    decimal_columns.push(header.to_string());
+                    overrides.with_column(header.clone(), DataType::String);
+                }
             }
         }
 
-        let param_refs: Vec<&dyn ToSql> = params.iter().map(|p| p.as_ref()).collect();
+        // Columns left out of the projection aren't there to convert
+        if let Some(columns) = &projection {
+            decimal_columns.retain(|header| {
+                headers
+                    .index_of(header)
+                    .is_some_and(|i| columns.contains(&i))
+            });
+        }
+        Some(Arc::new(overrides))
+    };
+
+    let configured_reader = || -> PolarsResult<_> {
+        Ok(configure_csv_reader(new_reader()?, options)
+            .with_dtypes(overrides.clone())
+            .with_projection(projection.clone()))
+    };
+
+    let mut df = match configured_reader()?.finish() {
+        Ok(df) => df,
+        Err(err) => read_with_text_fallback(configured_reader, options, err)?,
+    };
+    convert_decimal_columns(&mut df, &decimal_columns, options)?;
+    Ok(df)
+}
 
-        conn.execute(&insert_sql, &param_refs[..])?;
-    }
+// Convert decimal columns read as text to exact Decimal columns. A column
+// with values that aren't plain decimals, e.g. "1e-3", is loaded as REAL
+// instead, or as TEXT if it doesn't hold numbers after all.
+fn convert_decimal_columns(
+    df: &mut DataFrame,
+    columns: &[String],
+    options: &CsvOptions,
+) -> Result<(), Box<dyn Error>> {
+    for name in columns {
+        let text = df.column(name)?.clone();
+        let row = match decimal::to_decimal_column(&text)? {
+            Ok(decimals) => {
+                df.replace(name, decimals)?;
+                continue;
+            }
+            Err(decimal::NotDecimal::TooPrecise(row)) => {
+                let value = text.get(row).map(|v| v.to_string()).unwrap_or_default();
+                if options.is_overridden(name) {
+                    return Err(format!(
+                        "Column '{}' can't be read as DECIMAL: {} in data row {} has more than {} significant digits",
+                        name,
+                        value,
+                        row + 1,
+                        decimal::MAX_PRECISION
+                    )
+                    .into());
+                }
+                eprintln!(
+                    "Warning: column '{}' has more than {} significant digits in data row {} ({}); loading it as TEXT",
+                    name,
+                    decimal::MAX_PRECISION,
+                    row + 1,
+                    value
+                );
+                continue;
+            }
+            Err(decimal::NotDecimal::Invalid(row)) => row,
+        };
+
+        let value = text.get(row).map(|v| v.to_string()).unwrap_or_default();
+        if options.is_overridden(name) {
+            return Err(format!(
+                "Column '{}' can't be read as DECIMAL: invalid value {} in data row {}",
+                name,
+                value,
+                row + 1
+            )
+            .into());
+        }
 
+        let floats = text.cast(&DataType::Float64)?;
+        let failed_row = (0..text.len()).find(|&i| {
+            !matches!(text.get(i), Ok(AnyValue::Null))
+                && matches!(floats.get(i), Ok(AnyValue::Null))
+        });
+        match failed_row {
+            None => {
+                df.replace(name, floats)?;
+            }
+            Some(row) => eprintln!(
+                "Warning: column '{}' looked like REAL, but data row {} contains {}; loading it as TEXT",
+                name,
+                row + 1,
+                text.get(row).map(|v| v.to_string()).unwrap_or_default()
+            ),
+        }
+    }
     Ok(())
 }
 
-// Updated function to load CSV from memory using Polars
-fn load_csv_from_memory_with_polars(
+// Convert a column read as text to another type. Values that can't be
+// converted become null.
+fn cast_text_column(text: &Series, dtype: &DataType) -> Option<Series> {
+    if *dtype != DataType::Boolean {
+        return text.cast(dtype).ok();
+    }
+
+    // Polars can't cast strings to booleans, so parse them like the CSV reader
+    let values: BooleanChunked = text
+        .str()
+        .ok()?
+        .into_iter()
+        .map(|v| match v.map(str::to_lowercase).as_deref() {
+            Some("true") => Some(true),
+            Some("false") => Some(false),
+            _ => None,
+        })
+        .collect();
+    Some(values.with_name(text.name()).into_series())
+}
+
+// Column names and types a CSV reader would use, without reading all the data
+fn inferred_schema<'a, R: MmapBytesReader + 'a>(reader: CsvReader<'a, R>) -> PolarsResult<Schema> {
+    // Polars only limits the rows to read approximately, so values that don't
+    // fit the inferred types may still be parsed
+    Ok(reader
+        .with_n_rows(Some(0))
+        .with_ignore_errors(true)
+        .finish()?
+        .schema())
+}
+
+/// A CSV file scanned lazily
+struct CsvScan {
+    /// The columns, named as read_csv names them
+    frame: LazyFrame,
+    /// Header in the file for each column
+    headers: Vec<String>,
+    names: Vec<String>,
+}
+
+// Scan a plain CSV file lazily, so Polars only parses the columns and rows a
+// query uses. None if the data needs more than Polars' CSV reader: a footer
+// or malformed rows to handle, exact decimals, unsigned or date columns to
+// check, or all rows to infer types from. The first rows are read to tell
+// whether text columns hold dates.
+fn scan_csv(path: &Path, options: &CsvOptions) -> Result<Option<CsvScan>, Box<dyn Error>> {
+    if sniff::detect_file(path)? != Format::Csv
+        || options.skip_footer > 0
+        || options.on_error != OnError::Fail
+        || options.exact_decimals
+        || options.date_format.is_some()
+        || !options.column_date_formats.is_empty()
+        || options.infer_rows.is_none()
+    {
+        return Ok(None);
+    }
+    let converted_later = |dtype: &DataType| {
+        matches!(
+            dtype,
+            DataType::Date | DataType::Datetime(..) | DataType::Decimal(..) | DataType::UInt64
+        )
+    };
+    if options
+        .column_types
+        .iter()
+        .any(|(_, dtype)| converted_later(dtype))
+    {
+        return Ok(None);
+    }
+
+    let Ok(sample) = configure_csv_reader(CsvReader::from_path(path)?, options)
+        .with_n_rows(options.infer_rows)
+        .finish()
+    else {
+        return Ok(None);
+    };
+    for column in sample.get_columns() {
+        if options.is_overridden(column.name()) {
+            continue;
+        }
+        if converted_later(column.dtype()) {
+            return Ok(None);
+        }
+        // A text column is only known not to hold dates if one of its
+        // values isn't a date
+        if *column.dtype() == DataType::String
+            && (column.null_count() == column.len()
+                || !matches!(dates::detect(column, None)?, Detection::NotDates { .. }))
+        {
+            return Ok(None);
+        }
+    }
+
+    // As with the eager reader, only headers that exist are overridden
+    let read_names = sample.get_column_names();
+    let overrides = options
+        .column_types
+        .iter()
+        .filter(|(header, _)| read_names.contains(&header.as_str()))
+        .map(|(header, dtype)| Field::new(header, dtype.clone()))
+        .collect::<Schema>();
+    let (headers, names) = column_names(&read_names, options);
+    let frame = LazyCsvReader::new(path)
+        .has_header(true)
+        .with_infer_schema_length(options.infer_rows)
+        .with_skip_rows(options.skip_rows)
+        .with_comment_prefix(options.comment_char.as_deref())
+        .with_null_values(
+            (!options.null_values.is_empty())
+                .then(|| NullValues::AllColumns(options.null_values.clone())),
+        )
+        .with_dtype_overwrite(Some(&overrides))
+        .finish()?
+        .select(
+            read_names
+                .iter()
+                .zip(&names)
+                .map(|(read_name, name)| col(read_name).alias(name))
+                .collect::<Vec<_>>(),
+        );
+    Ok(Some(CsvScan {
+        frame,
+        headers,
+        names,
+    }))
+}
+
+// Reload CSV data whose values don't match the types inferred from the first
+// rows. Every column is read as text and then converted to its inferred type;
+// columns with values that can't be converted stay TEXT.
+fn read_with_text_fallback<'a, R: MmapBytesReader + 'a>(
+    configured_reader: impl Fn() -> PolarsResult<CsvReader<'a, R>>,
+    options: &CsvOptions,
+    err: PolarsError,
+) -> Result<DataFrame, Box<dyn Error>> {
+    let schema = inferred_schema(configured_reader()?)?;
+    let as_text = schema
+        .iter_names()
+        .map(|name| Field::new(name, DataType::String))
+        .collect::<Schema>();
+
+    // If the data can't even be read as text, the types weren't the problem
+    let Ok(mut df) = configured_reader()?
+        .with_dtypes(Some(Arc::new(as_text)))
+        .finish()
+    else {
+        return Err(err.into());
+    };
+
+    for (name, dtype) in schema.iter() {
+        if *dtype == DataType::String {
+            continue;
+        }
+
+        let text = df.column(name)?.clone();
+        let typed = cast_text_column(&text, dtype);
+        // A value that didn't survive the conversion turned into a null
+        let failed_row = match &typed {
+            Some(typed) => (0..text.len()).find(|&i| {
+                !matches!(text.get(i), Ok(AnyValue::Null))
+                    && matches!(typed.get(i), Ok(AnyValue::Null))
+            }),
+            None => Some(0),
+        };
+
+        let Some(row) = failed_row else {
+            df.replace(name, typed.unwrap())?;
+            continue;
+        };
+
+        let value = text.get(row).map(|v| v.to_string()).unwrap_or_default();
+        let type_str = polars_to_sqlite_type(dtype);
+        if options.is_overridden(name) {
+            return Err(format!(
+                "Column '{}' can't be read as {}: invalid value {} in data row {}",
+                name,
+                type_str,
+                value,
+                row + 1
+            )
+            .into());
+        }
+        eprintln!(
+            "Warning: column '{}' looked like {}, but data row {} contains {}; loading it as TEXT",
+            name,
+            type_str,
+            row + 1,
+            value
+        );
+    }
+
+    Ok(df)
+}
+
+fn configure_csv_reader<'a, R: MmapBytesReader + 'a>(
+    reader: CsvReader<'a, R>,
+    options: &CsvOptions,
+) -> CsvReader<'a, R> {
+    reader
+        .infer_schema(options.infer_rows)
+        .has_header(true)
+        .with_skip_rows(options.skip_rows)
+        .with_comment_prefix(options.comment_char.as_deref())
+        .with_null_values(
+            (!options.null_values.is_empty())
+                .then(|| NullValues::AllColumns(options.null_values.clone())),
+        )
+}
+
+// Remove the last `lines` non-empty lines from CSV data
+fn strip_footer(data: &[u8], lines: usize) -> &[u8] {
+    let mut end = data.len();
+    for _ in 0..lines {
+        // Skip trailing line breaks, then the line itself
+        while end > 0 && matches!(data[end - 1], b'\n' | b'\r') {
+            end -= 1;
+        }
+        while end > 0 && data[end - 1] != b'\n' {
+            end -= 1;
+        }
+    }
+    &data[..end]
+}
+
+// Rows converted and inserted at a time
+const INSERT_BATCH_ROWS: usize = 10_000;
+
+// A CSV input to read: table name, data, options, and the part of it the
+// query can use
+type CsvInput<'a> = (
+    String,
+    CsvSource<'a>,
+    CsvOptions,
+    Option<&'a pushdown::Pushdown>,
+);
+
+// Load each CSV input into a new table
+fn load_csv_tables(conn: &Connection, inputs: &[CsvInput]) -> Result<(), Box<dyn Error>> {
+    read_csv_tables(inputs, |table_name, table, options| {
+        insert_csv_table(conn, table_name, table, options)
+    })
+}
+
+// Read CSV inputs on several threads and pass each table to `add`. Reading
+// and type inference take most of the time, while SQLite can only insert
+// from one thread, so tables are passed on here in input order as soon as
+// they are read. If inputs fail to load, all errors are reported in input
+// order.
+fn read_csv_tables(
+    inputs: &[CsvInput],
+    mut add: impl FnMut(&str, CsvTable, &CsvOptions) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let workers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(inputs.len());
+    let next_input = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    let mut errors = Vec::new();
+    thread::scope(|scope| -> Result<(), Box<dyn Error>> {
+        for _ in 0..workers {
+            let sender = sender.clone();
+            let next_input = &next_input;
+            scope.spawn(move || loop {
+                let i = next_input.fetch_add(1, Ordering::Relaxed);
+                let Some((_, source, options, pushdown)) = inputs.get(i) else {
+                    break;
+                };
+                let table =
+                    read_csv_for_query(*source, options, *pushdown).and_then(|mut table| {
+                        if let Some(pushdown) = pushdown {
+                            table.df = pushdown.apply(table.df)?;
+                        }
+                        Ok(table)
+                    });
+                // Errors aren't Send, so only their messages are passed on
+                let table = table.map_err(|e| e.to_string());
+                if sender.send((i, table)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        // Tables read ahead of the next one to insert
+        let mut read = HashMap::new();
+        for (i, (table_name, _, options, _)) in inputs.iter().enumerate() {
+            while !read.contains_key(&i) {
+                let (j, table) = receiver.recv()?;
+                read.insert(j, table);
+            }
+            match read.remove(&i) {
+                Some(Ok(table)) if errors.is_empty() => add(table_name, table, options)?,
+                Some(Err(e)) => errors.push(format!("Failed to load '{}': {}", table_name, e)),
+                _ => {}
+            }
+        }
+        Ok(())
+    })?;
+
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0).into()),
+        n => {
+            for error in &errors {
+                eprintln!("Error: {}", error);
+            }
+            Err(format!("{} inputs failed to load", n).into())
+        }
+    }
+}
+
+// Load a CSV table into a new table. All rows are inserted in one transaction
+// with a single prepared statement, converting the columns batch by batch.
+fn insert_csv_table(
     conn: &Connection,
     table_name: &str,
-    data: &[u8],
+    table: CsvTable,
+    options: &CsvOptions,
 ) -> Result<(), Box<dyn Error>> {
-    // Use Polars to read the CSV with type inference
-    let df = CsvReader::new(io::Cursor::new(data))
-        .infer_schema(Some(100))
-        .has_header(true)
-        .finish()?;
+    let tx = conn.unchecked_transaction()?;
+    let mut insert = create_table(&tx, table_name, &table.df)?;
+    insert_dataframe(&mut insert, &table.df, options)?;
+    drop(insert);
+
+    store_rejects(&tx, table_name, &table.rejects, options)?;
+    tx.commit()?;
+    Ok(())
+}
 
+// Create a table with the columns of a DataFrame, returning the statement
+// to insert rows with
+fn create_table<'c>(
+    conn: &'c Connection,
+    table_name: &str,
+    df: &DataFrame,
+) -> Result<rusqlite::Statement<'c>, Box<dyn Error>> {
     // Create table with appropriate column types
-    let mut create_table_sql = format!("CREATE TABLE '{}' (", table_name);
     let columns = df
         .schema()
         .iter()
         .map(|(name, dtype)| {
             let sqlite_type = polars_to_sqlite_type(dtype);
-            format!("'{}' {}", name, sqlite_type)
+            format!("{} {}", quote_identifier(name), sqlite_type)
         })
         .collect::<Vec<_>>()
         .join(", ");
+    conn.execute(
+        &format!(
+            "CREATE TABLE {} ({})",
+            quote_identifier(table_name),
+            columns
+        ),
+        [],
+    )?;
 
-    create_table_sql.push_str(&columns);
-    create_table_sql.push_str(")");
+    let placeholders = vec!["?"; df.width()].join(", ");
+    Ok(conn.prepare(&format!(
+        "INSERT INTO {} VALUES ({})",
+        quote_identifier(table_name),
+        placeholders
+    ))?)
+}
 
-    conn.execute(&create_table_sql, [])?;
+fn insert_dataframe(
+    insert: &mut rusqlite::Statement,
+    df: &DataFrame,
+    options: &CsvOptions,
+) -> Result<(), Box<dyn Error>> {
+    for offset in (0..df.height()).step_by(INSERT_BATCH_ROWS) {
+        let mut batch = df.slice(offset as i64, INSERT_BATCH_ROWS);
+        batch.as_single_chunk();
+        let values = batch
+            .get_columns()
+            .iter()
+            .map(|column| {
+                column
+                    .iter()
+                    .map(|v| convert_any_value_to_sql(v, &options.null_values))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        for row in 0..batch.height() {
+            for (i, column) in values.iter().enumerate() {
+                insert.raw_bind_parameter(i + 1, &column[row])?;
+            }
+            insert.raw_execute()?;
+        }
+    }
+    Ok(())
+}
 
-    // Prepare placeholders for the insert statement
-    let placeholders = vec!["?"; df.width()].join(", ");
-    let insert_sql = format!("INSERT INTO '{}' VALUES ({})", table_name, placeholders);
+// Report malformed rows left out of a table. In quarantine mode they are
+// kept in a TABLE__rejects table, which exists even if all rows were fine.
+fn store_rejects(
+    conn: &Connection,
+    table_name: &str,
+    rejects: &[RejectedRow],
+    options: &CsvOptions,
+) -> Result<(), Box<dyn Error>> {
+    if options.on_error == OnError::Quarantine {
+        let rejects_table = quote_identifier(&rejects_table_name(table_name));
+        conn.execute(
+            &format!(
+                "CREATE TABLE {} (line INTEGER, raw TEXT, error TEXT)",
+                rejects_table
+            ),
+            [],
+        )?;
+        let mut stmt = conn.prepare(&format!(
+            "INSERT INTO {} VALUES (?1, ?2, ?3)",
+            rejects_table
+        ))?;
+        for row in rejects {
+            stmt.execute(rusqlite::params![row.line as i64, row.raw, row.error])?;
+        }
+    }
 
-    // Insert data row by row
-    for row_idx in 0..df.height() {
-        let mut params: Vec<Box<dyn ToSql>> = Vec::with_capacity(df.width());
+    report_rejects(table_name, rejects, options);
+    Ok(())
+}
 
-        for col_idx in 0..df.width() {
-            let series = &df.get_columns()[col_idx];
-            let value = series.get(row_idx);
-            match value {
-                Ok(any_value) => params.push(convert_any_value_to_sql(any_value)),
-                Err(_) => params.push(Box::new(Option::<String>::None)),
-            }
+// Write the loaded tables and those of a resumed session to a new SQLite
+// file. Loaded tables replace session tables of the same name.
+fn save_db(
+    conn: &Connection,
+    path: &Path,
+    loaded_tables: &[String],
+    attached: &[(String, Vec<String>)],
+) -> Result<(), Box<dyn Error>> {
+    let session_tables = attached
+        .iter()
+        .filter(|(schema, _)| schema == SESSION_SCHEMA)
+        .flat_map(|(_, tables)| tables)
+        .filter(|t| !loaded_tables.iter().any(|l| l.eq_ignore_ascii_case(t)));
+
+    write_database(conn, path, |schema| {
+        for table in loaded_tables {
+            copy_table(conn, None, table, schema, table)?;
+        }
+        for table in session_tables {
+            copy_table(conn, Some(SESSION_SCHEMA), table, schema, table)?;
         }
+        Ok(())
+    })
+}
 
-        let param_refs: Vec<&dyn ToSql> = params.iter().map(|p| p.as_ref()).collect();
+// Schema a new database file is attached as while it is written
+const NEW_DB_SCHEMA: &str = "pirkle_new_db";
 
-        conn.execute(&insert_sql, &param_refs[..])?;
+// Create a SQLite file with tables created by `fill` in the schema it gets.
+// The file is written as `<path>.partial-<pid>` first and renamed when it's
+// complete, so `path` never holds a partial file. A failed run removes the
+// partial file, but an interrupted one leaves it behind.
+fn write_database(
+    conn: &Connection,
+    path: &Path,
+    fill: impl FnOnce(&str) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(format!(".partial-{}", std::process::id()));
+    let partial = PathBuf::from(partial);
+
+    conn.execute(
+        "ATTACH DATABASE ?1 AS ?2",
+        [partial.to_string_lossy().as_ref(), NEW_DB_SCHEMA],
+    )?;
+    let result = (|| {
+        conn.execute_batch(&format!("PRAGMA {}.journal_mode = OFF", NEW_DB_SCHEMA))?;
+        let tx = conn.unchecked_transaction()?;
+        fill(NEW_DB_SCHEMA)?;
+        tx.commit()?;
+        Ok(())
+    })();
+    conn.execute("DETACH DATABASE ?1", [NEW_DB_SCHEMA])?;
+
+    match result {
+        Ok(()) => Ok(fs::rename(&partial, path)?),
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
     }
+}
+
+// Copy a table or view into a new table. The columns are declared with the
+// same types, which keeps types like BOOLEAN that control the output. An
+// unqualified source is looked up like in a query.
+fn copy_table(
+    conn: &Connection,
+    source_schema: Option<&str>,
+    source: &str,
+    target_schema: &str,
+    target: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut stmt = conn.prepare("SELECT name, type FROM pragma_table_info(?1, ?2)")?;
+    let columns = stmt
+        .query_map(rusqlite::params![source, source_schema], |row| {
+            Ok(format!(
+                "{} {}",
+                quote_identifier(&row.get::<_, String>(0)?),
+                row.get::<_, String>(1)?
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?
+        .join(", ");
 
+    let source = match source_schema {
+        Some(schema) => format!("{}.{}", quote_identifier(schema), quote_identifier(source)),
+        None => quote_identifier(source),
+    };
+    let target = format!(
+        "{}.{}",
+        quote_identifier(target_schema),
+        quote_identifier(target)
+    );
+    conn.execute_batch(&format!(
+        "CREATE TABLE {target} ({columns});
+         INSERT INTO {target} SELECT * FROM {source};"
+    ))?;
     Ok(())
 }
+
+fn rejects_table_name(table_name: &str) -> String {
+    format!("{}__rejects", table_name)
+}
+
+fn report_rejects(table_name: &str, rejects: &[RejectedRow], options: &CsvOptions) {
+    let Some(first) = rejects.first() else {
+        return;
+    };
+    let action = match options.on_error {
+        OnError::Quarantine => format!("moved to '{}'", rejects_table_name(table_name)),
+        _ => "skipped".to_string(),
+    };
+    eprintln!(
+        "Warning: {} malformed row(s) in '{}' {} (first at line {}: {})",
+        rejects.len(),
+        table_name,
+        action,
+        first.line,
+        first.error
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> CsvOptions {
+        CsvOptions {
+            infer_rows: Some(100),
+            skip_rows: 0,
+            comment_char: None,
+            skip_footer: 0,
+            null_values: Vec::new(),
+            column_types: Vec::new(),
+            column_renames: Vec::new(),
+            date_format: None,
+            column_date_formats: Vec::new(),
+            exact_decimals: false,
+            normalize_columns: NameStyle::None,
+            on_error: OnError::Fail,
+        }
+    }
+
+    #[test]
+    fn sqlite_inputs_are_read_only_unless_writable() {
+        let path = std::env::temp_dir().join(format!("pirkle-test-{}.sqlite", std::process::id()));
+        Connection::open(&path)
+            .and_then(|conn| conn.execute_batch("CREATE TABLE t (x INTEGER)"))
+            .unwrap();
+        let file = InputFile {
+            path: path.clone(),
+            uri_params: None,
+            tables: None,
+        };
+        let insert = |writable| {
+            let conn = Connection::open_in_memory().unwrap();
+            conn.execute("ATTACH DATABASE ?1 AS db", [file.sqlite_uri(writable)])
+                .unwrap();
+            conn.execute("INSERT INTO db.t VALUES (1)", [])
+        };
+        let refused = insert(false);
+        let allowed = insert(true);
+        fs::remove_file(&path).unwrap();
+
+        assert!(refused.unwrap_err().to_string().contains("readonly"));
+        assert_eq!(allowed.unwrap(), 1);
+    }
+
+    #[test]
+    fn snake_case_names() {
+        assert_eq!(to_snake_case("Order ID"), "order_id");
+        assert_eq!(to_snake_case("customerName"), "customer_name");
+        assert_eq!(to_snake_case("Amount ($)"), "amount");
+        assert_eq!(to_snake_case("  unit-price  "), "unit_price");
+        assert_eq!(to_snake_case("2nd Place"), "_2nd_place");
+        assert_eq!(to_snake_case("total2Paid"), "total2_paid");
+        assert_eq!(to_snake_case("($)"), "");
+    }
+
+    #[test]
+    fn column_names_are_unique() {
+        let options = CsvOptions {
+            normalize_columns: NameStyle::Snake,
+            ..options()
+        };
+        let (headers, names) = column_names(&["Order ID", "", "order_id"], &options);
+        assert_eq!(headers, ["Order ID", "", "order_id"]);
+        assert_eq!(names, ["order_id", "column_2", "order_id_2"]);
+
+        // Polars numbers repeated headers itself
+        let (headers, names) = column_names(&["notes", "notes_duplicated_0"], &options);
+        assert_eq!(headers, ["notes", "notes"]);
+        assert_eq!(names, ["notes", "notes_2"]);
+    }
+
+    #[test]
+    fn split_bad_rows_keeps_the_good_ones() {
+        let options = CsvOptions {
+            skip_rows: 1,
+            comment_char: Some("#".to_string()),
+            ..options()
+        };
+        let data = b"Report\nid,name\n1,Al\n# note\n\n2,Bob,extra\n3,\"Cy\n\"\n\xff,x\n4,Di\n";
+        let (clean, rejects) = split_bad_rows(data, &options).unwrap();
+        assert_eq!(clean, b"Report\nid,name\n1,Al\n3,\"Cy\n\"\n4,Di\n");
+
+        let rejects = rejects
+            .iter()
+            .map(|r| (r.line, r.raw.as_str(), r.error.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rejects,
+            [
+                (6, "2,Bob,extra", "expected 2 fields, found 3"),
+                (9, "\u{fffd},x", "invalid UTF-8"),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_bad_rows_rejects_an_unterminated_quote() {
+        // The quoted field runs to the end of the data
+        let data = b"id,name,age\n1,Al,20\n2,\"Bob,30\n3,Cy,40\n";
+        let (clean, rejects) = split_bad_rows(data, &options()).unwrap();
+        assert_eq!(clean, b"id,name,age\n1,Al,20\n");
+        assert_eq!(rejects.len(), 1);
+        assert_eq!(rejects[0].line, 3);
+        assert_eq!(rejects[0].error, "expected 3 fields, found 2");
+    }
+
+    #[test]
+    fn strip_footer_removes_non_empty_lines() {
+        let data = b"a,b\n1,2\n\nTotal,2\n\n";
+        assert_eq!(strip_footer(data, 0), data);
+        assert_eq!(strip_footer(data, 1), b"a,b\n1,2\n\n");
+        assert_eq!(strip_footer(data, 2), b"a,b\n");
+        assert_eq!(strip_footer(b"a,b\r\n1,2\r\nTotal", 1), b"a,b\r\n1,2\r\n");
+        assert_eq!(strip_footer(b"a,b\n", 3), b"");
+    }
+}
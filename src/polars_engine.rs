@@ -0,0 +1,194 @@
+// Running queries with Polars (--engine polars).
+//
+// The CSV inputs are read with the same options as for SQLite, but instead
+// of being inserted into a database, they are registered with Polars' SQL
+// context, which runs the compiled query on the data frames. This saves
+// converting every value to SQLite and back. Plain CSV files are registered
+// as lazy scans, so Polars only parses what the query needs. Polars
+// understands less SQL than SQLite, so some queries only run with the
+// default engine.
+
+use std::error::Error;
+use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
+
+use polars::prelude::*;
+use polars::sql::SQLContext;
+use rusqlite::types::{ToSqlOutput, ValueRef};
+
+use crate::sniff::{self, Format};
+use crate::{
+    convert_any_value_to_sql, format_value, polars_to_sqlite_type, print_results, read_csv_tables,
+    rejects_table_name, report_rejects, scan_csv, CompiledQuery, CsvArgs, CsvOptions, CsvSource,
+    CsvTable, InputFile, OnError, RejectedRow,
+};
+
+/// Run a query on the CSV inputs it reads with Polars
+pub fn run_query(
+    query: &CompiledQuery,
+    files: &[InputFile],
+    stdin_tables: &[(String, String)],
+    format: &str,
+    csv_args: &CsvArgs,
+) -> Result<(), Box<dyn Error>> {
+    if files.iter().any(InputFile::is_sqlite) {
+        return Err("--engine polars can't query SQLite files".into());
+    }
+    if csv_args.stream || csv_args.vtab || csv_args.cache {
+        return Err("--engine polars can't be used with --stream, --vtab or --cache".into());
+    }
+
+    let mut context = SQLContext::new();
+    let mut inputs = Vec::new();
+    let mut scanned = Vec::new();
+    for file in files {
+        let table_name = file.table_name();
+        if query.references(&table_name) {
+            let options = csv_args.options_for(&table_name, Some(&file.path))?;
+            let pushdown = query.pushdown(&table_name);
+            match scan_csv(&file.path, &options)? {
+                Some(scan) => {
+                    context.register(&table_name, scan.frame);
+                    scanned.push((table_name, CsvSource::File(&file.path), options, pushdown));
+                }
+                None => inputs.push((table_name, CsvSource::File(&file.path), options, pushdown)),
+            }
+        }
+    }
+
+    // All stdin tables are read from the same data
+    let used_stdin_tables = stdin_tables
+        .iter()
+        .filter(|(table_name, _)| query.references(table_name))
+        .collect::<Vec<_>>();
+    let mut stdin_data = Vec::new();
+    if !used_stdin_tables.is_empty() {
+        if atty::is(atty::Stream::Stdin) {
+            return Err("Stdin tables specified, but no data available from stdin".into());
+        }
+        io::stdin().read_to_end(&mut stdin_data)?;
+        if stdin_data.is_empty() {
+            return Err("Stdin tables specified, but no data received from stdin".into());
+        }
+        if sniff::detect(&stdin_data) == Format::Sqlite {
+            return Err("--engine polars can't query SQLite data".into());
+        }
+    }
+    for (table_name, _) in used_stdin_tables {
+        let options = csv_args.options_for(table_name, None)?;
+        let pushdown = query.pushdown(table_name);
+        inputs.push((
+            table_name.clone(),
+            CsvSource::Memory(&stdin_data),
+            options,
+            pushdown,
+        ));
+    }
+
+    read_csv_tables(&inputs, |table_name, table, options| {
+        register_table(&mut context, table_name, table, options)
+    })?;
+
+    let result = match execute(&mut context, &query.sql) {
+        // A value that doesn't fit the types inferred from the first rows
+        // makes a scan fail. Those files are read like the others then.
+        Err(_) if !scanned.is_empty() => {
+            read_csv_tables(&scanned, |table_name, table, options| {
+                register_table(&mut context, table_name, table, options)
+            })?;
+            execute(&mut context, &query.sql)
+        }
+        result => result,
+    };
+    let mut result = result.map_err(|e| {
+        format!(
+            "The polars engine can't run this query: {}\nIt may run with the default engine (--engine sqlite)",
+            e
+        )
+    })?;
+    result.as_single_chunk();
+
+    let column_names = result
+        .get_column_names()
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    // Values print as they would from SQLite, where the declared type tells
+    // how to print booleans and decimals
+    let decl_types = result
+        .dtypes()
+        .iter()
+        .map(polars_to_sqlite_type)
+        .collect::<Vec<_>>();
+    let rows = (0..result.height())
+        .map(|i| {
+            result
+                .get_columns()
+                .iter()
+                .zip(&decl_types)
+                .map(|(column, decl_type)| Ok(format_any_value(column.get(i)?, decl_type)))
+                .collect::<PolarsResult<Vec<_>>>()
+        })
+        .collect::<PolarsResult<Vec<_>>>()?;
+
+    print_results(format, &column_names, &rows)
+}
+
+fn register_table(
+    context: &mut SQLContext,
+    table_name: &str,
+    table: CsvTable,
+    options: &CsvOptions,
+) -> Result<(), Box<dyn Error>> {
+    if options.on_error == OnError::Quarantine {
+        let rejects = rejects_frame(&table.rejects)?;
+        context.register(&rejects_table_name(table_name), rejects.lazy());
+    }
+    report_rejects(table_name, &table.rejects, options);
+    context.register(table_name, table.df.lazy());
+    Ok(())
+}
+
+// Run a query. Polars panics on some queries it can't run instead of
+// returning an error, which is reported the same way.
+fn execute(context: &mut SQLContext, sql: &str) -> Result<DataFrame, String> {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        context.execute(sql).and_then(LazyFrame::collect)
+    }));
+    panic::set_hook(hook);
+    match result {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(panic) => Err(panic
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "internal error".to_string())),
+    }
+}
+
+// The rejected rows of a CSV input as a table, like the one stored in SQLite
+fn rejects_frame(rejects: &[RejectedRow]) -> PolarsResult<DataFrame> {
+    DataFrame::new(vec![
+        Series::new("line", rejects.iter().map(|r| r.line).collect::<Vec<_>>()),
+        Series::new(
+            "raw",
+            rejects.iter().map(|r| r.raw.as_str()).collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "error",
+            rejects.iter().map(|r| r.error.as_str()).collect::<Vec<_>>(),
+        ),
+    ])
+}
+
+fn format_any_value(value: AnyValue, decl_type: &str) -> Option<String> {
+    let value = convert_any_value_to_sql(value, &[]);
+    let value = match &value {
+        ToSqlOutput::Borrowed(value) => *value,
+        ToSqlOutput::Owned(value) => value.into(),
+        _ => ValueRef::Null,
+    };
+    format_value(value, Some(decl_type))
+}
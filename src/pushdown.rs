@@ -0,0 +1,478 @@
+// Loading only the part of a CSV file a query can use.
+//
+// The query's relational form tells which columns of each input table it
+// reads, and which simple conditions (a column compared with a literal) its
+// rows have to meet before anything else happens to them. Only those columns
+// and rows are inserted into SQLite. The query still applies all of its
+// filters, so a condition that is pushed down only has to keep every row
+// the query might need; conditions that can't be checked exactly the way
+// SQLite would are simply not pushed down.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use polars::prelude::*;
+use prql_compiler::ir::pl::{Literal, TableExternRef};
+use prql_compiler::ir::rq::{
+    CId, Expr, ExprKind, RelationColumn, RelationKind, RelationalQuery, RqFold, TId, TableRef,
+    Transform,
+};
+
+/// What a query reads from an input table
+#[derive(Debug, Default)]
+pub struct Pushdown {
+    /// Names of the columns used, lowercased; None if all are
+    columns: Option<HashSet<String>>,
+    /// Conditions every row used has to meet
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug)]
+struct Condition {
+    column: String,
+    operator: Comparison,
+    value: Literal,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+}
+
+impl Comparison {
+    fn from_operator(name: &str) -> Option<Comparison> {
+        match name {
+            "std.eq" => Some(Comparison::Eq),
+            "std.ne" => Some(Comparison::Ne),
+            "std.lt" => Some(Comparison::Lt),
+            "std.lte" => Some(Comparison::Lte),
+            "std.gt" => Some(Comparison::Gt),
+            "std.gte" => Some(Comparison::Gte),
+            _ => None,
+        }
+    }
+
+    // The comparison with its operands swapped, as in `5 < x` to `x > 5`
+    fn flip(self) -> Comparison {
+        match self {
+            Comparison::Lt => Comparison::Gt,
+            Comparison::Lte => Comparison::Gte,
+            Comparison::Gt => Comparison::Lt,
+            Comparison::Gte => Comparison::Lte,
+            other => other,
+        }
+    }
+}
+
+/// What the query reads from each of its input tables, by lowercased name
+pub fn query_pushdowns(rq: &RelationalQuery) -> HashMap<String, Pushdown> {
+    let inputs = rq
+        .tables
+        .iter()
+        .filter_map(|table| match &table.relation.kind {
+            RelationKind::ExternRef(TableExternRef::LocalTable(ident)) if ident.path.is_empty() => {
+                Some((table.id, ident.name.to_lowercase()))
+            }
+            _ => None,
+        })
+        .collect::<HashMap<TId, String>>();
+
+    let mut references = References::default();
+    if references.fold_query(rq.clone()).is_err() {
+        return HashMap::new();
+    }
+
+    let mut uses = Vec::new();
+    let relations = rq.tables.iter().map(|t| &t.relation).chain([&rq.relation]);
+    for relation in relations {
+        if let RelationKind::Pipeline(transforms) = &relation.kind {
+            pipeline_uses(transforms, &inputs, &references.0, &mut uses);
+        }
+    }
+
+    let mut pushdowns: HashMap<String, Pushdown> = HashMap::new();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for (table_name, columns, conditions) in uses {
+        *counts.entry(table_name.clone()).or_default() += 1;
+        match pushdowns.get_mut(&table_name) {
+            Some(pushdown) => {
+                pushdown.columns = match (pushdown.columns.take(), columns) {
+                    (Some(a), Some(b)) => Some(a.union(&b).cloned().collect()),
+                    _ => None,
+                };
+            }
+            None => {
+                pushdowns.insert(
+                    table_name,
+                    Pushdown {
+                        columns,
+                        conditions,
+                    },
+                );
+            }
+        }
+    }
+    // Rows filtered out for one use of a table may be needed by another
+    for (table_name, count) in counts {
+        if count > 1 {
+            if let Some(pushdown) = pushdowns.get_mut(&table_name) {
+                pushdown.conditions.clear();
+            }
+        }
+    }
+    pushdowns
+}
+
+// The columns a query refers to anywhere, by id. The columns a table is
+// read with don't count, as every table is read with a wildcard for the
+// columns the query doesn't name.
+#[derive(Default)]
+struct References(HashSet<CId>);
+
+impl RqFold for References {
+    fn fold_cid(&mut self, cid: CId) -> prql_compiler::Result<CId> {
+        self.0.insert(cid);
+        Ok(cid)
+    }
+
+    fn fold_table_ref(&mut self, table_ref: TableRef) -> prql_compiler::Result<TableRef> {
+        Ok(table_ref)
+    }
+}
+
+type Use = (String, Option<HashSet<String>>, Vec<Condition>);
+
+// The uses of input tables in a pipeline: the columns used and, for the
+// table the pipeline starts from, the conditions its rows have to meet
+fn pipeline_uses(
+    transforms: &[Transform],
+    inputs: &HashMap<TId, String>,
+    references: &HashSet<CId>,
+    uses: &mut Vec<Use>,
+) {
+    for (i, transform) in transforms.iter().enumerate() {
+        let table = match transform {
+            Transform::From(table) | Transform::Join { with: table, .. } => table,
+            Transform::Append(table) => table,
+            Transform::Loop(transforms) => {
+                pipeline_uses(transforms, inputs, references, uses);
+                continue;
+            }
+            _ => continue,
+        };
+        let Some(table_name) = inputs.get(&table.source) else {
+            continue;
+        };
+        let conditions = if i == 0 && matches!(transform, Transform::From(_)) {
+            leading_conditions(&transforms[1..], table)
+        } else {
+            Vec::new()
+        };
+        let columns = used_columns(table, references);
+        uses.push((table_name.clone(), columns, conditions));
+    }
+}
+
+// The named columns of a table, None if the query also uses the columns it
+// doesn't name (e.g. it ends with the whole table)
+fn used_columns(table: &TableRef, references: &HashSet<CId>) -> Option<HashSet<String>> {
+    let mut columns = HashSet::new();
+    for (column, id) in &table.columns {
+        match column {
+            RelationColumn::Single(Some(name)) => {
+                columns.insert(name.to_lowercase());
+            }
+            RelationColumn::Wildcard if !references.contains(id) => {}
+            _ => return None,
+        }
+    }
+    Some(columns)
+}
+
+// The conditions of the filters that apply to the rows of `table` as read,
+// i.e. that come before any transform that combines, limits or numbers rows
+fn leading_conditions(transforms: &[Transform], table: &TableRef) -> Vec<Condition> {
+    let columns = table
+        .columns
+        .iter()
+        .filter_map(|(column, id)| match column {
+            RelationColumn::Single(Some(name)) => Some((*id, name.clone())),
+            _ => None,
+        })
+        .collect::<HashMap<CId, String>>();
+
+    let mut conditions = Vec::new();
+    for transform in transforms {
+        match transform {
+            Transform::Filter(expr) => simple_conditions(expr, &columns, &mut conditions),
+            Transform::Select(_) | Transform::Sort(_) => {}
+            Transform::Compute(compute) if compute.window.is_none() && !compute.is_aggregation => {}
+            _ => break,
+        }
+    }
+    conditions
+}
+
+// The parts of a condition that compare a column with a literal
+fn simple_conditions(expr: &Expr, columns: &HashMap<CId, String>, conditions: &mut Vec<Condition>) {
+    let ExprKind::Operator { name, args } = &expr.kind else {
+        return;
+    };
+    if name == "std.and" {
+        for arg in args {
+            simple_conditions(arg, columns, conditions);
+        }
+        return;
+    }
+    let (Some(operator), [left, right]) = (Comparison::from_operator(name), args.as_slice()) else {
+        return;
+    };
+    let (column, operator, value) = match (&left.kind, &right.kind) {
+        (ExprKind::ColumnRef(id), ExprKind::Literal(value)) => (id, operator, value),
+        (ExprKind::Literal(value), ExprKind::ColumnRef(id)) => (id, operator.flip(), value),
+        _ => return,
+    };
+    if let Some(column) = columns.get(column) {
+        conditions.push(Condition {
+            column: column.clone(),
+            operator,
+            value: value.clone(),
+        });
+    }
+}
+
+impl Pushdown {
+    /// Positions of the columns the query uses among `names`, None if it
+    /// uses all of them. The first column is kept if the query uses none,
+    /// e.g. to count rows, as a table needs a column.
+    pub fn projection<S: AsRef<str>>(&self, names: &[S]) -> Option<Vec<usize>> {
+        let columns = self.columns.as_ref()?;
+        let mut used = (0..names.len())
+            .filter(|&i| columns.contains(&names[i].as_ref().to_lowercase()))
+            .collect::<Vec<_>>();
+        if used.is_empty() {
+            used.push(0);
+        }
+        (used.len() < names.len()).then_some(used)
+    }
+
+    /// Select the columns and rows the query can use from a scan, so that
+    /// Polars doesn't parse the others
+    pub fn scan(&self, frame: LazyFrame) -> PolarsResult<LazyFrame> {
+        let schema = frame.schema()?;
+        let mut frame = frame;
+        for condition in &self.conditions {
+            let Some((name, dtype)) = schema
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(&condition.column))
+            else {
+                continue;
+            };
+            if let Some(expr) = condition.expr(name, dtype) {
+                frame = frame.filter(expr);
+            }
+        }
+
+        let names = schema.iter_names().collect::<Vec<_>>();
+        let Some(projection) = self.projection(&names) else {
+            return Ok(frame);
+        };
+        let columns = projection
+            .iter()
+            .map(|&i| col(names[i]))
+            .collect::<Vec<_>>();
+        Ok(frame.select(columns))
+    }
+
+    /// Keep only the columns and rows the query can use
+    pub fn apply(&self, df: DataFrame) -> Result<DataFrame, Box<dyn Error>> {
+        let mut mask: Option<BooleanChunked> = None;
+        for condition in &self.conditions {
+            let Some(column) = df
+                .get_columns()
+                .iter()
+                .find(|c| c.name().eq_ignore_ascii_case(&condition.column))
+            else {
+                continue;
+            };
+            if let Some(matches) = condition.evaluate(column)? {
+                mask = Some(match mask {
+                    Some(mask) => mask & matches,
+                    None => matches,
+                });
+            }
+        }
+        let df = match mask {
+            Some(mask) => df.filter(&mask)?,
+            None => df,
+        };
+
+        let names = df.get_column_names();
+        let Some(projection) = self.projection(&names) else {
+            return Ok(df);
+        };
+        Ok(df.select(projection.iter().map(|&i| names[i]))?)
+    }
+}
+
+impl Condition {
+    // The condition as a filter on the column `name`, None if it can't be
+    // told the way SQLite would
+    fn expr(&self, name: &str, dtype: &DataType) -> Option<polars::prelude::Expr> {
+        // The same values as in evaluate()
+        let value = match &self.value {
+            Literal::Integer(n) if dtype.is_numeric() => lit(*n),
+            Literal::Float(x) if dtype.is_numeric() => lit(*x),
+            Literal::String(s) if dtype == &DataType::String => lit(s.as_str()),
+            _ => return None,
+        };
+        let column = col(name);
+        Some(match self.operator {
+            Comparison::Eq => column.eq(value),
+            Comparison::Ne => column.neq(value),
+            Comparison::Lt => column.lt(value),
+            Comparison::Lte => column.lt_eq(value),
+            Comparison::Gt => column.gt(value),
+            Comparison::Gte => column.gt_eq(value),
+        })
+    }
+
+    // Which values of the column meet the condition, None if it can't be
+    // told the way SQLite would. Null values never do.
+    fn evaluate(&self, column: &Series) -> PolarsResult<Option<BooleanChunked>> {
+        let dtype = column.dtype();
+        // Only numbers and text compare the same way here as in SQLite.
+        // Text that is stored as NULL (see --null-values) may be kept,
+        // which is fine, as the query filters it out again.
+        let value = match &self.value {
+            Literal::Integer(n) if dtype.is_numeric() => Series::new("", [*n]),
+            Literal::Float(x) if dtype.is_numeric() => Series::new("", [*x]),
+            Literal::String(s) if dtype == &DataType::String => Series::new("", [s.as_str()]),
+            _ => return Ok(None),
+        };
+        let matches = match self.operator {
+            Comparison::Eq => column.equal(&value)?,
+            Comparison::Ne => column.not_equal(&value)?,
+            Comparison::Lt => column.lt(&value)?,
+            Comparison::Lte => column.lt_eq(&value)?,
+            Comparison::Gt => column.gt(&value)?,
+            Comparison::Gte => column.gt_eq(&value)?,
+        };
+        // Comparisons with nulls are null, which filter() drops
+        Ok(Some(matches))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pushdowns(prql: &str) -> HashMap<String, Pushdown> {
+        let rq = prql_compiler::prql_to_pl(prql)
+            .and_then(prql_compiler::pl_to_rq)
+            .unwrap();
+        query_pushdowns(&rq)
+    }
+
+    fn condition(operator: Comparison, value: Literal) -> Condition {
+        Condition {
+            column: "x".to_string(),
+            operator,
+            value,
+        }
+    }
+
+    fn matches(condition: &Condition, column: &Series) -> Option<Vec<Option<bool>>> {
+        let matches = condition.evaluate(column).unwrap()?;
+        Some(matches.into_iter().collect())
+    }
+
+    #[test]
+    fn evaluate_compares_numbers_and_text() {
+        let numbers = Series::new("x", [Some(1.5), None, Some(3.0)]);
+        let gt = condition(Comparison::Gt, Literal::Integer(2));
+        assert_eq!(
+            matches(&gt, &numbers),
+            Some(vec![Some(false), None, Some(true)])
+        );
+        let lte = condition(Comparison::Lte, Literal::Float(1.5));
+        assert_eq!(
+            matches(&lte, &numbers),
+            Some(vec![Some(true), None, Some(false)])
+        );
+
+        let text = Series::new("x", ["a", "b"]);
+        let ne = condition(Comparison::Ne, Literal::String("a".to_string()));
+        assert_eq!(matches(&ne, &text), Some(vec![Some(false), Some(true)]));
+    }
+
+    #[test]
+    fn evaluate_skips_what_sqlite_compares_differently() {
+        // SQLite orders all numbers before all text
+        let text = Series::new("x", ["10", "9"]);
+        let gt = condition(Comparison::Gt, Literal::Integer(5));
+        assert_eq!(matches(&gt, &text), None);
+
+        let numbers = Series::new("x", [1, 2]);
+        let eq = condition(Comparison::Eq, Literal::String("1".to_string()));
+        assert_eq!(matches(&eq, &numbers), None);
+        let eq = condition(Comparison::Eq, Literal::Boolean(true));
+        assert_eq!(matches(&eq, &numbers), None);
+    }
+
+    #[test]
+    fn scan_keeps_the_same_rows_as_apply() {
+        let df = df!("x" => [1, 5, 10], "y" => ["a", "b", "c"], "z" => [0, 0, 0]).unwrap();
+        let pushdown = pushdowns("from t | filter x > 2 | select {x, y}")
+            .remove("t")
+            .unwrap();
+
+        let applied = pushdown.apply(df.clone()).unwrap();
+        let scanned = pushdown.scan(df.lazy()).unwrap().collect().unwrap();
+        assert_eq!(applied.get_column_names(), ["x", "y"]);
+        assert_eq!(applied.column("x").unwrap(), &Series::new("x", [5, 10]));
+        assert!(applied.equals(&scanned));
+    }
+
+    #[test]
+    fn query_pushdowns_of_tables() {
+        let pushdowns = pushdowns(
+            "from a | filter 3 <= x | join b (==id) | filter b.y == 'n' | select {a.x, b.y}",
+        );
+        let a = &pushdowns["a"];
+        let b = &pushdowns["b"];
+
+        // Only the conditions on the table the pipeline starts from, up to the join
+        assert_eq!(a.conditions.len(), 1);
+        assert!(matches!(a.conditions[0].operator, Comparison::Gte));
+        assert!(b.conditions.is_empty());
+
+        let names = ["id", "x", "y"].map(String::from);
+        assert_eq!(a.projection(&names), Some(vec![0, 1]));
+        assert_eq!(b.projection(&names), Some(vec![0, 2]));
+    }
+
+    #[test]
+    fn tables_used_twice_keep_all_rows() {
+        let pushdowns = pushdowns(
+            "from a | filter x > 1 | select {x} | append (from a | filter x < 0 | select {x})",
+        );
+        assert!(pushdowns["a"].conditions.is_empty());
+        assert_eq!(
+            pushdowns["a"].columns,
+            Some(HashSet::from(["x".to_string()]))
+        );
+    }
+
+    #[test]
+    fn whole_tables_need_all_columns() {
+        for query in ["from t | filter x > 2", "from t | derive {y = x + 1}"] {
+            assert_eq!(pushdowns(query)["t"].columns, None, "{}", query);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Recognizing input formats by their content.
+//
+// Files and piped data are told apart by their first bytes instead of a file
+// extension, so `data.sqlite3` is opened as a database and a gzipped CSV file
+// can be piped in. Anything that isn't recognized is read as CSV.
+
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+
+/// Format of an input file or of piped data
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Sqlite,
+    Gzip,
+    Parquet,
+    /// A JSON array of objects, or one object per line
+    Json,
+}
+
+// Bytes read from the start of a file to recognize its format
+const SNIFF_LEN: usize = 64;
+
+/// Recognize the format of data from its first bytes
+pub fn detect(prefix: &[u8]) -> Format {
+    if prefix.starts_with(b"SQLite format 3\0") {
+        return Format::Sqlite;
+    }
+    if prefix.starts_with(&[0x1f, 0x8b]) {
+        return Format::Gzip;
+    }
+    if prefix.starts_with(b"PAR1") {
+        return Format::Parquet;
+    }
+
+    // A CSV header can't start with a bracket or brace, but JSON does
+    let text = prefix.strip_prefix(b"\xef\xbb\xbf").unwrap_or(prefix);
+    match text.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'[' | b'{') => Format::Json,
+        _ => Format::Csv,
+    }
+}
+
+/// Recognize the format of a file
+pub fn detect_file(path: &Path) -> io::Result<Format> {
+    let mut prefix = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    Ok(detect(&prefix))
+}
+
+/// Recognize the format of piped data without consuming any of it. The data
+/// is peeked at in stdin's buffer, so later reads of stdin still see all of
+/// it. None if there is no data.
+pub fn peek_stdin() -> io::Result<Option<Format>> {
+    let stdin = io::stdin();
+    let mut handle = stdin.lock();
+    let buffered = handle.fill_buf()?;
+    if buffered.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(detect(buffered)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_formats_by_their_first_bytes() {
+        assert_eq!(detect(b"SQLite format 3\0\x10\0"), Format::Sqlite);
+        assert_eq!(detect(&[0x1f, 0x8b, 0x08, 0x00]), Format::Gzip);
+        assert_eq!(detect(b"PAR1\x15\x04"), Format::Parquet);
+        assert_eq!(detect(b"[{\"id\": 1}]"), Format::Json);
+        assert_eq!(detect(b"\xef\xbb\xbf\n  {\"id\": 1}\n"), Format::Json);
+    }
+
+    #[test]
+    fn detect_falls_back_to_csv() {
+        assert_eq!(detect(b"id,name\n1,Al\n"), Format::Csv);
+        assert_eq!(detect(b"\xef\xbb\xbfid,name\n"), Format::Csv);
+        assert_eq!(detect(b"SQLite format"), Format::Csv);
+        assert_eq!(detect(b""), Format::Csv);
+    }
+}
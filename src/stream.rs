@@ -0,0 +1,410 @@
+// Streaming CSV ingestion (--stream).
+//
+// Column types are inferred from the first rows, which are read like a whole
+// file would be. The remaining rows are converted and inserted one by one as
+// they are read, so the data is never held in memory outside of SQLite.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read};
+
+use csv::ByteRecord;
+use polars::prelude::*;
+use rusqlite::types::Value;
+use rusqlite::Connection;
+
+use crate::dates::{self, DateFormat, Detection};
+use crate::{
+    create_table, insert_dataframe, polars_to_sqlite_type, read_csv, store_rejects, CsvOptions,
+    CsvSource, CsvTable, OnError, RejectedRow,
+};
+
+/// How to convert the text of a field to the type of its column
+enum Converter {
+    Text,
+    Integer,
+    Real,
+    Boolean,
+    Date(DateFormat, DataType),
+}
+
+impl Converter {
+    fn for_column(dtype: &DataType, date_format: Option<DateFormat>) -> Converter {
+        match (dtype, date_format) {
+            (DataType::Date | DataType::Datetime(..), Some(format)) => {
+                Converter::Date(format, dtype.clone())
+            }
+            (DataType::Boolean, _) => Converter::Boolean,
+            (DataType::Float32 | DataType::Float64 | DataType::Decimal(..), _) => Converter::Real,
+            (dtype, _) if dtype.is_integer() => Converter::Integer,
+            _ => Converter::Text,
+        }
+    }
+
+    // Convert a value, or return None if it doesn't fit the column type
+    fn convert(&self, text: &str) -> Option<Value> {
+        match self {
+            Converter::Text => Some(Value::Text(text.to_string())),
+            Converter::Integer => match text.parse::<i64>() {
+                Ok(v) => Some(Value::Integer(v)),
+                // Unsigned values beyond the INTEGER range are kept as text
+                Err(_) => text
+                    .parse::<u64>()
+                    .ok()
+                    .map(|_| Value::Text(text.to_string())),
+            },
+            Converter::Real => text.parse::<f64>().ok().map(Value::Real),
+            Converter::Boolean => match text.to_lowercase().as_str() {
+                "true" => Some(Value::Integer(1)),
+                "false" => Some(Value::Integer(0)),
+                _ => None,
+            },
+            Converter::Date(format, dtype) => {
+                dates::normalize_value(text, format, dtype).map(Value::Text)
+            }
+        }
+    }
+}
+
+/// Data read from a stream without its last `lines` non-empty lines, the
+/// same footer that strip_footer removes from data in memory
+struct WithoutFooter<R: BufRead> {
+    input: R,
+    lines: usize,
+    /// Lines held back until it is clear they aren't part of the footer
+    pending: VecDeque<Vec<u8>>,
+    /// Non-empty lines among the pending ones
+    non_empty: usize,
+    /// Lines that aren't part of the footer, and how much of them was read
+    released: Vec<u8>,
+    read: usize,
+}
+
+impl<R: BufRead> WithoutFooter<R> {
+    fn new(input: R, lines: usize) -> Self {
+        WithoutFooter {
+            input,
+            lines,
+            pending: VecDeque::new(),
+            non_empty: 0,
+            released: Vec::new(),
+            read: 0,
+        }
+    }
+}
+
+// Whether a line has anything besides line breaks
+fn is_non_empty(line: &[u8]) -> bool {
+    line.iter().any(|b| !matches!(b, b'\n' | b'\r'))
+}
+
+impl<R: BufRead> Read for WithoutFooter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read == self.released.len() {
+            let mut line = Vec::new();
+            if self.input.read_until(b'\n', &mut line)? == 0 {
+                // Whatever is still pending is the footer
+                return Ok(0);
+            }
+            self.non_empty += is_non_empty(&line) as usize;
+            self.pending.push_back(line);
+
+            self.released.clear();
+            self.read = 0;
+            while let Some(first) = self.pending.front() {
+                let after = self.non_empty - is_non_empty(first) as usize;
+                if after < self.lines {
+                    break;
+                }
+                self.non_empty = after;
+                self.released
+                    .extend(self.pending.pop_front().unwrap_or_default());
+            }
+        }
+
+        let n = buf.len().min(self.released.len() - self.read);
+        buf[..n].copy_from_slice(&self.released[self.read..self.read + n]);
+        self.read += n;
+        Ok(n)
+    }
+}
+
+/// CSV rows read from a stream, with malformed rows and the footer removed
+pub struct Rows<R: Read> {
+    reader: csv::Reader<WithoutFooter<BufReader<R>>>,
+    /// Lines skipped before the header
+    preamble_lines: u64,
+    header_fields: Option<usize>,
+    on_error: OnError,
+    pub rejects: Vec<RejectedRow>,
+}
+
+impl<R: Read> Rows<R> {
+    pub fn new(mut input: BufReader<R>, options: &CsvOptions) -> Result<Self, Box<dyn Error>> {
+        let mut preamble_lines = 0;
+        let mut line = Vec::new();
+        for _ in 0..options.skip_rows {
+            line.clear();
+            if input.read_until(b'\n', &mut line)? > 0 {
+                preamble_lines += 1;
+            }
+        }
+
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .comment(options.comment_char.as_ref().map(|c| c.as_bytes()[0]))
+            .from_reader(WithoutFooter::new(input, options.skip_footer));
+        Ok(Rows {
+            reader,
+            preamble_lines,
+            header_fields: None,
+            on_error: options.on_error,
+            rejects: Vec::new(),
+        })
+    }
+
+    // The next well-formed row (the header first) with its line number
+    pub fn next(&mut self) -> Result<Option<(u64, ByteRecord)>, Box<dyn Error>> {
+        loop {
+            let line = self.preamble_lines + self.reader.position().line();
+            let mut record = ByteRecord::new();
+            if !self.reader.read_byte_record(&mut record)? {
+                return Ok(None);
+            }
+
+            let expected = *self.header_fields.get_or_insert(record.len());
+            let error = if record.len() != expected {
+                Some(format!(
+                    "expected {} fields, found {}",
+                    expected,
+                    record.len()
+                ))
+            } else if record
+                .iter()
+                .any(|field| std::str::from_utf8(field).is_err())
+            {
+                Some("invalid UTF-8".to_string())
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                if self.on_error == OnError::Fail {
+                    return Err(format!("Malformed CSV row at line {}: {}", line, error).into());
+                }
+                self.rejects.push(RejectedRow {
+                    line,
+                    raw: raw_text(&record),
+                    error,
+                });
+                continue;
+            }
+            return Ok(Some((line, record)));
+        }
+    }
+}
+
+// A row written back as CSV, for the rejects table
+fn raw_text(record: &ByteRecord) -> String {
+    let mut writer = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_writer(Vec::new());
+    let raw = match writer.write_byte_record(record) {
+        Ok(()) => writer.into_inner().unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+    String::from_utf8_lossy(&raw)
+        .trim_end_matches(['\r', '\n'])
+        .to_string()
+}
+
+// Read the header and the rows to infer types from, and load them like a
+// whole file. Returns the table read from them and the rows read.
+pub fn read_prefix<R: Read>(
+    rows: &mut Rows<R>,
+    options: &CsvOptions,
+) -> Result<(CsvTable, Vec<ByteRecord>), Box<dyn Error>> {
+    let infer_rows = options
+        .infer_rows
+        .ok_or("--infer-rows all can't be used with --stream")?;
+
+    let mut records = Vec::new();
+    while records.len() <= infer_rows {
+        match rows.next()? {
+            Some((_, record)) => records.push(record),
+            None => break,
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for record in &records {
+        writer.write_byte_record(record)?;
+    }
+    let data = writer.into_inner().map_err(|e| e.to_string())?;
+
+    // Preamble, comments, footer and malformed rows are already gone
+    let prefix_options = CsvOptions {
+        infer_rows: None,
+        skip_rows: 0,
+        comment_char: None,
+        skip_footer: 0,
+        on_error: OnError::Fail,
+        ..options.clone()
+    };
+    let table = read_csv(CsvSource::Memory(&data), &prefix_options)?;
+    Ok((table, records))
+}
+
+// Find the format the dates of a column were detected with in the first rows
+fn detected_date_format(
+    records: &[ByteRecord],
+    index: usize,
+    header: &str,
+    options: &CsvOptions,
+) -> Option<DateFormat> {
+    let values = records
+        .iter()
+        .skip(1)
+        .map(|r| r.get(index).map(String::from_utf8_lossy))
+        .map(|v| v.filter(|v| !v.is_empty() && !options.null_values.iter().any(|n| n == v)))
+        .collect::<Vec<_>>();
+    let series = values
+        .iter()
+        .map(|v| v.as_deref())
+        .collect::<StringChunked>()
+        .into_series();
+
+    let format = options
+        .column_date_formats
+        .iter()
+        .find(|(h, _)| h == header)
+        .map(|(_, f)| f)
+        .or(options.date_format.as_ref());
+    match dates::detect(&series, format) {
+        Ok(Detection::Dates(_, format)) => Some(format),
+        _ => None,
+    }
+}
+
+/// Conversion of the fields after the first rows to the inferred types
+pub struct Columns {
+    names: Vec<String>,
+    types: Vec<String>,
+    converters: Vec<Converter>,
+    /// Whether a value that doesn't fit was reported yet, per column
+    warned: Vec<Cell<bool>>,
+}
+
+impl Columns {
+    pub fn new(table: &CsvTable, records: &[ByteRecord], options: &CsvOptions) -> Columns {
+        let columns = table.df.get_columns();
+        let converters = columns
+            .iter()
+            .zip(&table.headers)
+            .enumerate()
+            .map(|(i, (column, header))| {
+                let date_format = matches!(column.dtype(), DataType::Date | DataType::Datetime(..))
+                    .then(|| detected_date_format(records, i, header, options))
+                    .flatten();
+                Converter::for_column(column.dtype(), date_format)
+            })
+            .collect::<Vec<_>>();
+        Columns {
+            names: columns.iter().map(|c| c.name().to_string()).collect(),
+            types: columns
+                .iter()
+                .map(|c| polars_to_sqlite_type(c.dtype()))
+                .collect(),
+            warned: converters.iter().map(|_| Cell::new(false)).collect(),
+            converters,
+        }
+    }
+
+    /// Convert field `i` of the row at `line`. The column type is already
+    /// fixed, so values that don't fit are kept as text, with a warning the
+    /// first time.
+    pub fn convert(&self, i: usize, field: &[u8], line: u64, options: &CsvOptions) -> Value {
+        // Fields are known to be valid UTF-8
+        let text = std::str::from_utf8(field).unwrap_or_default();
+        if text.is_empty() || options.null_values.iter().any(|n| n == text) {
+            return Value::Null;
+        }
+        match self.converters[i].convert(text) {
+            Some(value) => value,
+            None => {
+                if !self.warned[i].replace(true) {
+                    eprintln!(
+                        "Warning: column '{}' looked like {}, but line {} contains \"{}\"; storing such values as text",
+                        self.names[i], self.types[i], line, text
+                    );
+                }
+                Value::Text(text.to_string())
+            }
+        }
+    }
+}
+
+/// Load CSV data from a stream into a new table
+pub fn load_csv_streaming<R: Read>(
+    conn: &Connection,
+    table_name: &str,
+    input: R,
+    options: &CsvOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut rows = Rows::new(BufReader::new(input), options)?;
+    let (table, records) = read_prefix(&mut rows, options)?;
+
+    let tx = conn.unchecked_transaction()?;
+    let mut insert = create_table(&tx, table_name, &table.df)?;
+    insert_dataframe(&mut insert, &table.df, options)?;
+
+    let columns = Columns::new(&table, &records, options);
+    let mut values = Vec::with_capacity(table.df.width());
+    while let Some((line, record)) = rows.next()? {
+        values.clear();
+        for (i, field) in record.iter().enumerate() {
+            values.push(columns.convert(i, field, line, options));
+        }
+        insert.execute(rusqlite::params_from_iter(&values))?;
+    }
+    drop(insert);
+
+    let mut rejects = table.rejects;
+    rejects.append(&mut rows.rejects);
+    store_rejects(&tx, table_name, &rejects, options)?;
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn without_footer_removes_what_strip_footer_does() {
+        let inputs: [&[u8]; 5] = [
+            b"a,b\n1,2\n\nTotal,2\n\n",
+            b"a,b\r\n1,2\r\nTotal",
+            b"a,b\n1,\"x\n\"\nTotal\n",
+            b"a,b\n",
+            b"",
+        ];
+        for data in inputs {
+            for lines in 0..4 {
+                let mut read = Vec::new();
+                let mut reader = WithoutFooter::new(data, lines);
+                // Small reads, to cross the line boundaries
+                let mut buf = [0; 3];
+                loop {
+                    let n = reader.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    read.extend_from_slice(&buf[..n]);
+                }
+                assert_eq!(read, crate::strip_footer(data, lines), "{:?}", data);
+            }
+        }
+    }
+}
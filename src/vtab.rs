@@ -0,0 +1,210 @@
+// CSV files queried in place (--vtab).
+//
+// Each file is exposed through a read-only SQLite virtual table instead of
+// being copied into the database. Column types are inferred from the first
+// rows, as with --stream. Every scan reads the file from the start and only
+// converts the fields the query asks for, so a scan that stops early, like
+// `take 10`, only reads the start of the file.
+
+use std::cell::Cell;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use csv::ByteRecord;
+use rusqlite::types::Null;
+use rusqlite::vtab::{
+    read_only_module, sqlite3_vtab, sqlite3_vtab_cursor, Context, CreateVTab, IndexInfo, VTab,
+    VTabConnection, VTabCursor, VTabKind, Values,
+};
+use rusqlite::Connection;
+
+use crate::stream::{read_prefix, Columns, Rows};
+use crate::{polars_to_sqlite_type, quote_identifier, report_rejects, CsvOptions, OnError};
+
+/// A CSV file and what was inferred from its first rows
+struct CsvFile {
+    table_name: String,
+    path: PathBuf,
+    options: CsvOptions,
+    /// CREATE TABLE statement declaring the columns to SQLite
+    schema: String,
+    columns: Columns,
+    /// Whether skipped rows were reported, which happens after the first
+    /// complete scan
+    reported: Cell<bool>,
+}
+
+impl CsvFile {
+    // The rows of the file, with the header already read
+    fn rows(&self) -> Result<Rows<File>, Box<dyn Error>> {
+        let input = BufReader::new(File::open(&self.path)?);
+        let mut rows = Rows::new(input, &self.options)?;
+        rows.next()?;
+        Ok(rows)
+    }
+}
+
+/// Expose a CSV file as the virtual table `table_name`
+pub fn register(
+    conn: &Connection,
+    table_name: &str,
+    path: &Path,
+    options: &CsvOptions,
+) -> Result<(), Box<dyn Error>> {
+    if options.on_error == OnError::Quarantine {
+        return Err("--on-error quarantine can't be used with --vtab".into());
+    }
+
+    let input = BufReader::new(File::open(path)?);
+    let mut rows = Rows::new(input, options)?;
+    let (table, records) = read_prefix(&mut rows, options)?;
+
+    let columns = table
+        .df
+        .schema()
+        .iter()
+        .map(|(name, dtype)| {
+            format!(
+                "{} {}",
+                quote_identifier(name),
+                polars_to_sqlite_type(dtype)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let file = CsvFile {
+        table_name: table_name.to_string(),
+        path: path.to_path_buf(),
+        options: options.clone(),
+        schema: format!("CREATE TABLE x ({})", columns),
+        columns: Columns::new(&table, &records, options),
+        reported: Cell::new(false),
+    };
+
+    // One module per file, which carries what was inferred about it
+    let module = format!("csv_{}", table_name);
+    conn.create_module(&module, read_only_module::<CsvVTab>(), Some(Rc::new(file)))?;
+    conn.execute(
+        &format!(
+            "CREATE VIRTUAL TABLE {} USING {}",
+            quote_identifier(table_name),
+            quote_identifier(&module)
+        ),
+        [],
+    )?;
+    Ok(())
+}
+
+fn module_error(error: Box<dyn Error>) -> rusqlite::Error {
+    rusqlite::Error::ModuleError(error.to_string())
+}
+
+#[repr(C)]
+struct CsvVTab {
+    /// Base class, must be first
+    base: sqlite3_vtab,
+    file: Rc<CsvFile>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for CsvVTab {
+    type Aux = Rc<CsvFile>;
+    type Cursor = CsvCursor<'vtab>;
+
+    fn connect(
+        _: &mut VTabConnection,
+        aux: Option<&Rc<CsvFile>>,
+        _: &[&[u8]],
+    ) -> rusqlite::Result<(String, CsvVTab)> {
+        let file = aux.ok_or_else(|| rusqlite::Error::ModuleError("no CSV file".to_string()))?;
+        let vtab = CsvVTab {
+            base: sqlite3_vtab::default(),
+            file: Rc::clone(file),
+        };
+        Ok((file.schema.clone(), vtab))
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        // Every scan reads the whole file
+        info.set_estimated_cost(1_000_000.0);
+        Ok(())
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<CsvCursor<'vtab>> {
+        Ok(CsvCursor {
+            base: sqlite3_vtab_cursor::default(),
+            file: Rc::clone(&self.file),
+            rows: None,
+            row: None,
+            rowid: 0,
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl CreateVTab<'_> for CsvVTab {
+    const KIND: VTabKind = VTabKind::Default;
+}
+
+#[repr(C)]
+struct CsvCursor<'vtab> {
+    /// Base class, must be first
+    base: sqlite3_vtab_cursor,
+    file: Rc<CsvFile>,
+    rows: Option<Rows<File>>,
+    /// The current row and its line number, None at the end
+    row: Option<(u64, ByteRecord)>,
+    rowid: i64,
+    phantom: PhantomData<&'vtab CsvVTab>,
+}
+
+unsafe impl VTabCursor for CsvCursor<'_> {
+    fn filter(&mut self, _: c_int, _: Option<&str>, _: &Values<'_>) -> rusqlite::Result<()> {
+        // Each scan starts over at the first row
+        self.rows = Some(self.file.rows().map_err(module_error)?);
+        self.rowid = 0;
+        self.next()
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        let Some(rows) = self.rows.as_mut() else {
+            self.row = None;
+            return Ok(());
+        };
+        self.row = rows.next().map_err(module_error)?;
+        self.rowid += 1;
+
+        if self.row.is_none() && !self.file.reported.replace(true) {
+            report_rejects(&self.file.table_name, &rows.rejects, &self.file.options);
+        }
+        Ok(())
+    }
+
+    fn eof(&self) -> bool {
+        self.row.is_none()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        let Some((line, record)) = &self.row else {
+            return ctx.set_result(&Null);
+        };
+        match record.get(i as usize) {
+            Some(field) => {
+                let value = self
+                    .file
+                    .columns
+                    .convert(i as usize, field, *line, &self.file.options);
+                ctx.set_result(&value)
+            }
+            None => ctx.set_result(&Null),
+        }
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        Ok(self.rowid)
+    }
+}
@@ -101,6 +101,23 @@ maybe_run_test "Query SQLite file" \
     "name.*age" \
     "[ -f examples/company.sqlite ]"
 
+# Select tables of a SQLite file
+maybe_run_test "Select SQLite tables" \
+    "$PIRKLE_BIN examples/company.sqlite:employees --schema" \
+    "Table: employees" \
+    "[ -f examples/company.sqlite ]"
+
+maybe_run_test "Unselected SQLite tables can't be queried" \
+    "! $PIRKLE_BIN examples/company.sqlite:employees -- \"from company.departments\" 2>&1 && $PIRKLE_BIN examples/company.sqlite:employees -- \"from departments\" 2>&1 | grep \"wasn't selected\"" \
+    "company.departments' wasn't selected" \
+    "[ -f examples/company.sqlite ]"
+
+# SQLite URI filenames are accepted, e.g. for a locked database
+maybe_run_test "Query SQLite URI filename" \
+    "$PIRKLE_BIN 'file:examples/company.sqlite?immutable=1' -f csv --query 'from employees | take 1 | select {name}'" \
+    "name.John Smith" \
+    "[ -f examples/company.sqlite ]"
+
 # Alternative syntax with -- delimiter
 run_test "Query with -- delimiter" \
     "$PIRKLE_BIN examples/employees.csv -- \"from employees | filter department == 'Engineering' | select {name, age}\"" \
@@ -142,6 +159,11 @@ run_test "Show SQL for PRQL file" \
     "$PIRKLE_BIN examples/employees.csv --query examples/queries/avg_age_by_department.prql --show-sql" \
     "SELECT.*department_id.*AVG.*age"
 
+# Show SQL for another dialect
+run_test "Show SQL for a target dialect" \
+    "$PIRKLE_BIN examples/employees.csv --query \"from employees | take 3\" --show-sql --target mssql --no-signature" \
+    "FETCH FIRST.*3 ROWS ONLY"
+
 # Output in CSV format
 run_test "Output in CSV format" \
     "$PIRKLE_BIN examples/employees.csv --format csv --query \"from employees | filter salary > 70000\"" \
@@ -184,6 +206,225 @@ run_test "Joining tables" \
     "$PIRKLE_BIN examples/orders.csv examples/customers.csv --query $TESTDIR/queries/join_query.prql" \
     "Acme Corp.*250"
 
+# Joining tables read in place, which can't be indexed
+run_test "Joining tables with --vtab" \
+    "$PIRKLE_BIN --vtab examples/orders.csv examples/customers.csv --query $TESTDIR/queries/join_query.prql" \
+    "Acme Corp.*250"
+
+# Cached CSV files, more than SQLite can attach at once
+mkdir -p $TESTDIR/many
+for i in $(seq 1 12); do printf 'a,b\n%d,x\n' $i > $TESTDIR/many/t$i.csv; done
+for run in "Caching" "Reusing cached"; do
+    run_test "$run CSV files with --cache" \
+        "XDG_CACHE_HOME=$PWD/$TESTDIR/cache $PIRKLE_BIN --cache $TESTDIR/many/*.csv --query 'from s\"SELECT * FROM t12\"'" \
+        "12.*x"
+done
+run_test "Listing cached files with cache ls" \
+    "XDG_CACHE_HOME=$PWD/$TESTDIR/cache $PIRKLE_BIN cache ls" \
+    "many/t12.csv"
+run_test "Removing cached files with cache clear" \
+    "XDG_CACHE_HOME=$PWD/$TESTDIR/cache $PIRKLE_BIN cache clear" \
+    "Removed 12 cached file"
+
+# A saved session can be reopened, also just to show its schema
+run_test "Saving a session with --save-db" \
+    "$PIRKLE_BIN examples/employees.csv --save-db $TESTDIR/session.sqlite < /dev/null && $PIRKLE_BIN --open-db $TESTDIR/session.sqlite --query 'from employees | take 1'" \
+    "John Smith"
+run_test "Schema of a session opened with --open-db" \
+    "$PIRKLE_BIN --open-db $TESTDIR/session.sqlite --schema" \
+    "Table: employees"
+
+# The query runs as generic SQL, whatever target its header names
+run_test "Query with a target in its header" \
+    "$PIRKLE_BIN examples/employees.csv --query 'prql target:sql.mssql
+from employees | take 1 | select {name}'" \
+    "John Smith"
+
+# Only the used columns and rows are loaded, named as if all were
+printf 'Notes,notes,x\n1,2,3\n4,5,6\n' > $TESTDIR/dup.csv
+run_test "Loading only the columns and rows a query uses" \
+    "$PIRKLE_BIN $TESTDIR/dup.csv --query 'from dup | filter x > 3 | select {notes_2}'" \
+    "notes_2[^0-9]*5 "
+run_test "Counting rows without loading any column the query names" \
+    "$PIRKLE_BIN examples/employees.csv -f jsonl --query 'from employees | aggregate {n = count this}'" \
+    '{"n":10}'
+
+# Exact decimals keep their scale in aggregates and JSON Lines
+printf 'id,amount\n1,0.10\n2,0.20\n3,1.00\n' > $TESTDIR/payments.csv
+run_test "Summing with --exact-decimals" \
+    "$PIRKLE_BIN $TESTDIR/payments.csv --exact-decimals -f jsonl --query 'from payments | aggregate {total = sum amount}'" \
+    '{"total":1.30}'
+printf 'id,price,tax\n1,1.10,0.20\n2,2.00,0.40\n' > $TESTDIR/prices.csv
+run_test "Summing with --exact-decimals and an unused decimal column" \
+    "$PIRKLE_BIN $TESTDIR/prices.csv --exact-decimals -f jsonl --query 'from prices | aggregate {total = sum price}'" \
+    '{"total":3.10}'
+run_test "Summing with --engine polars and an unused decimal column" \
+    "$PIRKLE_BIN $TESTDIR/prices.csv --engine polars --exact-decimals -f csv --query 'from prices | aggregate {total = sum price}'" \
+    "total.3.10"
+run_test "Summing only a DECIMAL column exactly" \
+    "$PIRKLE_BIN $TESTDIR/prices.csv --type price=DECIMAL -f jsonl --query 'from prices | aggregate {s = sum price, n = sum id}'" \
+    '{"n":3,"s":3.10}'
+run_test "Comparing an exact sum as a number" \
+    "$PIRKLE_BIN $TESTDIR/prices.csv --type price=DECIMAL -f csv --query 'from prices | group id (aggregate {s = sum price}) | filter s > 1.5'" \
+    "id,s.2,2"
+run_test "Leaving out an unused DECIMAL column" \
+    "$PIRKLE_BIN $TESTDIR/prices.csv --type tax=DECIMAL -f csv --query 'from prices | select {price}'" \
+    "price.1.1"
+
+# A streamed file loses the same footer lines as a whole one
+printf 'id,name,age\n1,Al,20\n2,Bob,30\n3,"Bad,40\nTotal,1\n' > $TESTDIR/footer.csv
+run_test "Streaming with --skip-footer and a malformed row" \
+    "$PIRKLE_BIN $TESTDIR/footer.csv --stream --skip-footer 1 --on-error quarantine --query 'from footer | filter age == 30'" \
+    "2.*Bob.*30"
+
+# Preamble, comment and footer lines around the data
+printf 'Exported 2024-01-01\nid,name\n# a comment\n1,Al\n2,Bob\nTotal,2\n' > $TESTDIR/report.csv
+run_test "Skipping lines with --skip-rows" \
+    "$PIRKLE_BIN $TESTDIR/report.csv --skip-rows 1 --comment-char '#' --skip-footer 1 --schema" \
+    "id (INTEGER)"
+run_test "Ignoring comment lines with --comment-char" \
+    "$PIRKLE_BIN $TESTDIR/report.csv --skip-rows 1 --comment-char '#' --skip-footer 1 -f jsonl --query 'from report | aggregate {n = count this}'" \
+    '{"n":2}'
+run_test "Skipping a footer with --skip-footer" \
+    "$PIRKLE_BIN $TESTDIR/report.csv --skip-rows 1 --comment-char '#' --skip-footer 1 -f csv --query 'from report | sort {-id} | take 1'" \
+    "2,Bob"
+
+# Custom NULL markers
+printf 'id,score\n1,NA\n2,7\n' > $TESTDIR/scores.csv
+run_test "Loading NULL markers with --null-values" \
+    "$PIRKLE_BIN $TESTDIR/scores.csv --null-values NA -f jsonl --query 'from scores | filter score == null'" \
+    '{"id":1,"score":null}'
+
+# Type overrides on the command line and in a sidecar schema file
+printf 'zip,amount\n01234,5\n' > $TESTDIR/zips.csv
+run_test "Overriding column types with --type" \
+    "$PIRKLE_BIN $TESTDIR/zips.csv --type zip=TEXT -f csv --query 'from zips | select {zip}'" \
+    "01234"
+printf 'Zip Code,amount\n01234,5\n' > $TESTDIR/addresses.csv
+cat > $TESTDIR/addresses.schema.toml << EOF
+[[columns]]
+name = "zip"
+header = "Zip Code"
+type = "TEXT"
+EOF
+run_test "Renaming and typing columns with a sidecar schema file" \
+    "$PIRKLE_BIN $TESTDIR/addresses.csv --schema" \
+    'zip (TEXT, overridden, header "Zip Code")'
+
+# A value after the inferred rows that doesn't fit the inferred type
+printf 'id,code\n1,1\n2,2\n3,3\n4,X4\n' > $TESTDIR/codes.csv
+run_test "Inferring types from fewer rows with --infer-rows" \
+    "$PIRKLE_BIN $TESTDIR/codes.csv --infer-rows 2 --schema" \
+    "looked like INTEGER.*code (TEXT)"
+run_test "Inferring types from all rows with --infer-rows all" \
+    "$PIRKLE_BIN $TESTDIR/codes.csv --infer-rows all --schema 2>&1 >/dev/null | wc -l" \
+    "^ *0"
+
+# Dates that could be day-first or month-first
+printf 'id,day\n1,01/02/2024\n2,03/04/2024\n' > $TESTDIR/days.csv
+run_test "Choosing the date format with --date-format" \
+    "$PIRKLE_BIN $TESTDIR/days.csv --date-format 'day=%d/%m/%Y' -f csv --query 'from days | take 1'" \
+    "1,2024-02-01"
+
+# Booleans and unsigned integers beyond SQLite's INTEGER range
+printf 'id,active,big\n1,true,18446744073709551615\n2,false,1\n' > $TESTDIR/flags.csv
+run_test "Loading booleans and large unsigned integers" \
+    "$PIRKLE_BIN $TESTDIR/flags.csv -f csv --query 'from flags | filter active' 2>/dev/null" \
+    "1,true,18446744073709551615"
+
+# Headers that need backticks in PRQL
+printf 'Order ID,Amount ($)\n1,5\n' > $TESTDIR/receipts.csv
+run_test "Rewriting column names with --normalize-columns" \
+    "$PIRKLE_BIN $TESTDIR/receipts.csv --normalize-columns snake -f csv --query 'from receipts | select {order_id, amount}'" \
+    "order_id,amount.*1,5"
+
+# A row with too many fields
+printf 'id,name\n1,Al\n2,Bob,extra\n3,Cy\n' > $TESTDIR/messy.csv
+run_test "Skipping malformed rows with --on-error skip" \
+    "$PIRKLE_BIN $TESTDIR/messy.csv --on-error skip -f jsonl --query 'from messy | aggregate {n = count this}'" \
+    '{"n":2}'
+run_test "Quarantining malformed rows with --on-error quarantine" \
+    "$PIRKLE_BIN $TESTDIR/messy.csv --on-error quarantine -f csv --query 'from messy__rejects'" \
+    "3,2,Bob,extra,expected 2 fields, found 3"
+
+# A working database on disk instead of in memory
+run_test "Querying with --temp-db" \
+    "$PIRKLE_BIN examples/employees.csv --temp-db -f csv --query 'from employees | take 1'" \
+    "1,John Smith"
+mkdir -p $TESTDIR/work
+run_test "Querying with --workdir" \
+    "$PIRKLE_BIN examples/employees.csv --workdir $TESTDIR/work -f csv --query 'from employees | sort name | take 1'" \
+    "Ahmed Hassan"
+
+# Join keys are indexed automatically, other columns on request
+run_test "Indexing join keys, reported with --verbose" \
+    "$PIRKLE_BIN examples/orders.csv examples/customers.csv --verbose --query $TESTDIR/queries/join_query.prql" \
+    "Built index pirkle_idx_orders_customer_id on orders(customer_id)"
+run_test "Indexing a column with --index" \
+    "$PIRKLE_BIN examples/orders.csv --verbose --index orders.amount --query 'from orders | filter amount > 100'" \
+    "Built index pirkle_idx_orders_amount on orders(amount)"
+
+# Queries run by Polars instead of SQLite
+run_test "Querying with --engine polars" \
+    "$PIRKLE_BIN examples/employees.csv --engine polars -f csv --query 'from employees | filter age > 40 | select {name}'" \
+    "Robert Johnson"
+
+# Queries run by DuckDB, if pirkle was built with it
+HAS_DUCKDB="! $PIRKLE_BIN examples/employees.csv --engine duckdb --query 'from employees' 2>&1 | grep -q 'built without DuckDB'"
+maybe_run_test "Querying with --engine duckdb" \
+    "$PIRKLE_BIN examples/employees.csv --engine duckdb -f csv --query 'from employees | filter age > 40 | select {name}'" \
+    "Robert Johnson" \
+    "$HAS_DUCKDB"
+printf '{"id":1,"name":"Al"}\n{"id":2,"name":"Bo"}\n' > $TESTDIR/people.json
+maybe_run_test "Querying a JSON file with --engine duckdb" \
+    "$PIRKLE_BIN $TESTDIR/people.json --engine duckdb -f csv --query 'from people | filter id == 2'" \
+    "2,Bo" \
+    "$HAS_DUCKDB"
+maybe_run_test "Naming repeated and empty headers with --engine duckdb" \
+    "printf 'Notes,notes,,x\\n1,2,3,4\\n' > $TESTDIR/headers.csv && $PIRKLE_BIN $TESTDIR/headers.csv --engine duckdb -f csv --query 'from headers'" \
+    "Notes,notes_2,column_3,x" \
+    "$HAS_DUCKDB"
+maybe_run_test "Querying stdin with --engine duckdb" \
+    "cat examples/employees.csv | $PIRKLE_BIN stdin --engine duckdb -f csv --query 'from stdin | filter age > 40 | select {name}'" \
+    "Robert Johnson" \
+    "$HAS_DUCKDB"
+
+# Piped data is recognized by its content
+run_test "Querying gzipped CSV data from stdin" \
+    "gzip -c examples/employees.csv | $PIRKLE_BIN stdin -f csv --query 'from stdin | filter age > 40 | select {name}'" \
+    "Robert Johnson"
+run_test "Querying JSON Lines from stdin" \
+    "printf '{\"id\":1,\"name\":\"Al\"}\\n{\"id\":2,\"name\":\"Bo\"}\\n' | $PIRKLE_BIN stdin -f csv --query 'from stdin | filter id == 2'" \
+    "2,Bo"
+run_test "Reading the query from stdin" \
+    "echo 'from employees | take 1 | select {name}' | $PIRKLE_BIN examples/employees.csv -f csv" \
+    "John Smith"
+
+# Inputs the query doesn't use aren't loaded
+printf 'a,b\n1,2,3\n' > $TESTDIR/broken.csv
+run_test "Ignoring an input the query doesn't use" \
+    "$PIRKLE_BIN examples/employees.csv $TESTDIR/broken.csv -f csv --query 'from employees | take 1 | select {name}'" \
+    "John Smith"
+
+# Inputs that fail to load are reported in the order they were given
+printf 'x,y\n4,5,6,7\n' > $TESTDIR/broken2.csv
+run_test "Reporting load errors in argument order" \
+    "! $PIRKLE_BIN $TESTDIR/broken2.csv $TESTDIR/broken.csv --query 'from s\"SELECT 1 AS one\"' 2>&1" \
+    "Failed to load 'broken2'.*Failed to load 'broken'.*2 inputs failed to load"
+
+# A CSV table takes priority over a SQLite table of the same name
+printf 'id,name\n99,Csv Person\n' > $TESTDIR/employees.csv
+maybe_run_test "Reading a CSV table before a SQLite table of the same name" \
+    "$PIRKLE_BIN $TESTDIR/employees.csv examples/company.sqlite -f csv --query 'from employees | select {name}' && $PIRKLE_BIN $TESTDIR/employees.csv examples/company.sqlite -f csv --query 'from company.employees | take 1 | select {name}'" \
+    "name.Csv Person.name.John Smith" \
+    "[ -f examples/company.sqlite ]"
+
+# A file cached as loaded normally isn't reused when it is streamed
+printf 'v\n1\nx\n' > $TESTDIR/mixed.csv
+run_test "Caching a streamed file separately" \
+    "XDG_CACHE_HOME=$PWD/$TESTDIR/cache $PIRKLE_BIN --cache $TESTDIR/mixed.csv --infer-rows 1 -f csv --query 'from s\"SELECT typeof(v) AS t FROM mixed\"' && XDG_CACHE_HOME=$PWD/$TESTDIR/cache $PIRKLE_BIN --cache --stream $TESTDIR/mixed.csv --infer-rows 1 -f csv --query 'from s\"SELECT typeof(v) AS t FROM mixed\"'" \
+    "t.text.text.*t.integer.text"
+
 # Print summary
 echo 
 echo -e "${GREEN}Tests passed: $TESTS_PASSED${NC}"